@@ -0,0 +1,76 @@
+use crate::err::{WickError, WickResult};
+use crate::manifest::AppManifest;
+use crate::version::BuildVersion;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+const APP_MANIFEST_FILE: &'static str = "app.json";
+const CHUNK_MANIFEST_FILE: &'static str = "chunks.manifest";
+
+// A directory of builds laid out as <build_version>/app.json and <build_version>/chunks.manifest
+pub struct ManifestArchive {
+    dir: PathBuf,
+}
+
+impl ManifestArchive {
+    pub fn new<P>(dir: P) -> Self where P: AsRef<Path> {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn build_dir(&self, build_version: &str) -> WickResult<PathBuf> {
+        // Build versions are mostly path-safe, but don't let one escape the archive
+        let name: String = build_version.chars().map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c => c,
+        }).collect();
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.dir.join(name)),
+            _ => Err(WickError::new_str(format!("{:?} can't be used as a build directory", build_version), 12)),
+        }
+    }
+
+    pub async fn store(&self, app_manifest: &AppManifest, chunk_manifest: &[u8]) -> WickResult<PathBuf> {
+        let build_dir = self.build_dir(app_manifest.get_build_version())?;
+        fs::create_dir_all(&build_dir).await?;
+        fs::write(build_dir.join(APP_MANIFEST_FILE), app_manifest.get_raw()).await?;
+        fs::write(build_dir.join(CHUNK_MANIFEST_FILE), chunk_manifest).await?;
+        Ok(build_dir)
+    }
+
+    // Every build with both manifests present
+    pub async fn get_builds(&self) -> WickResult<Vec<String>> {
+        let mut builds = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !path.join(APP_MANIFEST_FILE).is_file() || !path.join(CHUNK_MANIFEST_FILE).is_file() {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|v| v.to_str()) {
+                builds.push(name.to_owned());
+            }
+        }
+        builds.sort();
+        Ok(builds)
+    }
+
+    // Highest build by version number. Anything that doesn't parse as a build version sorts first.
+    pub async fn get_latest_build(&self) -> WickResult<Option<String>> {
+        let builds = self.get_builds().await?;
+        Ok(builds.into_iter().max_by_key(|v| BuildVersion::parse(v).ok()))
+    }
+
+    // Returns the app manifest JSON and chunk manifest data, ready for ServiceState::from_manifests
+    pub async fn load(&self, build_version: &str) -> WickResult<(String, Vec<u8>)> {
+        let build_dir = self.build_dir(build_version)?;
+        if !build_dir.is_dir() {
+            return Err(WickError::new_str(format!("Build {} is not in the archive", build_version), 12));
+        }
+        let app_manifest = fs::read_to_string(build_dir.join(APP_MANIFEST_FILE)).await?;
+        let chunk_manifest = fs::read(build_dir.join(CHUNK_MANIFEST_FILE)).await?;
+        Ok((app_manifest, chunk_manifest))
+    }
+}
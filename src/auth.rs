@@ -1,41 +1,307 @@
 use crate::http::HttpService;
-use crate::err::{WickResult, WickError};
-use hyper::{Request, Body};
-use serde::Deserialize;
+use crate::err::{WickError, WickResult, read_epic_json};
+use crate::token_cache::TokenCache;
+use hyper::{Request, Response, Body, StatusCode};
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc, Duration};
+use std::pin::Pin;
+use std::path::Path;
+use std::sync::Arc;
+use futures::Future;
+use tokio::sync::Mutex;
 
-const CREDENTIAL_URL: &'static str = "https://account-public-service-prod03.ol.epicgames.com/account/api/oauth/token";
-const CLIENT_POST_DATA: &'static str = "grant_type=client_credentials&token_token=eg1";
+pub const ACCOUNT_URL: &'static str = "https://account-public-service-prod03.ol.epicgames.com/account/api";
+const TOKEN_PATH: &'static str = "/oauth/token";
+pub(crate) const CLIENT_POST_DATA: &'static str = "grant_type=client_credentials&token_token=eg1";
 const EGS_AUTH: &'static str = "basic MzRhMDJjZjhmNDQxNGUyOWIxNTkyMTg3NmRhMzZmOWE6ZGFhZmJjY2M3Mzc3NDUwMzlkZmZlNTNkOTRmYzc2Y2Y=";
 
-#[derive(Deserialize, Debug)]
+// How long before the token actually expires that we go and fetch a new one
+const REFRESH_MARGIN_SECS: i64 = 300;
+
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(dead_code)]
 pub struct AccessToken {
     access_token: String,
     expires_in: i32,
     expires_at: String,
     token_type: String,
+    #[serde(default)]
     client_id: String,
+    #[serde(default)]
     internal_client: bool,
+    #[serde(default)]
     client_service: String,
+    #[serde(default)]
+    account_id: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    refresh_expires_at: Option<String>,
 }
 
 impl AccessToken {
+    // A pre-issued bearer token. With no known expiry it is used until the server rejects it.
+    pub fn from_bearer(access_token: &str) -> Self {
+        Self {
+            access_token: access_token.to_owned(),
+            expires_in: 0,
+            expires_at: String::new(),
+            token_type: "bearer".to_owned(),
+            client_id: String::new(),
+            internal_client: false,
+            client_service: String::new(),
+            account_id: None,
+            refresh_token: None,
+            refresh_expires_at: None,
+        }
+    }
+
     pub fn get_access_token(&self) -> &str {
         &self.access_token
     }
+
+    // Only present on tokens issued for a user account
+    pub fn get_account_id(&self) -> Option<&str> {
+        self.account_id.as_deref()
+    }
+
+    pub fn get_refresh_token(&self) -> Option<&str> {
+        self.refresh_token.as_deref()
+    }
+
+    pub fn get_refresh_expires_at(&self) -> Option<DateTime<Utc>> {
+        let expires_at = self.refresh_expires_at.as_ref()?;
+        DateTime::parse_from_rfc3339(expires_at).ok().map(|v| v.with_timezone(&Utc))
+    }
+
+    pub fn get_expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.expires_at).ok().map(|v| v.with_timezone(&Utc))
+    }
+
+    // If the expiry can't be parsed, keep using the token until the server rejects it.
+    fn needs_refresh(&self) -> bool {
+        match self.get_expires_at() {
+            Some(expires) => expires - Utc::now() < Duration::seconds(REFRESH_MARGIN_SECS),
+            None => false,
+        }
+    }
+}
+
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = WickResult<Option<AccessToken>>> + Send + 'a>>;
+
+// Something that can hand out tokens for the launcher service.
+// Returning None means requests are sent without an Authorization header.
+pub trait AuthProvider: Send + Sync {
+    fn fetch_token<'a>(&'a self, http: &'a HttpService, account_url: &'a str) -> TokenFuture<'a>;
+}
+
+pub struct ClientCredentials {
+    authorization: String,
 }
 
-pub async fn get_token(http: &HttpService) -> WickResult<AccessToken> {
+impl ClientCredentials {
+    pub fn new(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            authorization: "basic ".to_owned() + &base64::encode(format!("{}:{}", client_id, client_secret)),
+        }
+    }
+
+    pub(crate) fn get_authorization(&self) -> &str {
+        &self.authorization
+    }
+}
+
+// The launcher's own client, which is what we've always used.
+impl Default for ClientCredentials {
+    fn default() -> Self {
+        Self {
+            authorization: EGS_AUTH.to_owned(),
+        }
+    }
+}
+
+impl AuthProvider for ClientCredentials {
+    fn fetch_token<'a>(&'a self, http: &'a HttpService, account_url: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let token = request_token(http, account_url, &self.authorization, CLIENT_POST_DATA.to_owned()).await?;
+            Ok(Some(token))
+        })
+    }
+}
+
+pub struct StaticToken {
+    access_token: String,
+}
+
+impl StaticToken {
+    pub fn new(access_token: &str) -> Self {
+        Self {
+            access_token: access_token.to_owned(),
+        }
+    }
+}
+
+impl AuthProvider for StaticToken {
+    fn fetch_token<'a>(&'a self, _http: &'a HttpService, _account_url: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            Ok(Some(AccessToken::from_bearer(&self.access_token)))
+        })
+    }
+}
+
+pub struct NoAuth;
+
+impl AuthProvider for NoAuth {
+    fn fetch_token<'a>(&'a self, _http: &'a HttpService, _account_url: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            Ok(None)
+        })
+    }
+}
+
+// Stands in for the real provider in offline mode, so nothing ever reaches the account service
+pub(crate) struct Offline;
+
+impl AuthProvider for Offline {
+    fn fetch_token<'a>(&'a self, _http: &'a HttpService, _account_url: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            Err(WickError::new_str("The account service can't be used in offline mode".to_owned(), 17))
+        })
+    }
+}
+
+pub(crate) async fn post_token(http: &HttpService, account_url: &str, authorization: &str, post_data: String) -> WickResult<Response<BytesMut>> {
     let req = Request::builder()
         .method("POST")
-        .uri(CREDENTIAL_URL)
+        .uri(account_url.to_owned() + TOKEN_PATH)
         .header("Content-Type", "application/x-www-form-urlencoded")
-        .header("Authorization", EGS_AUTH)
-        .body(Body::from(CLIENT_POST_DATA))?;
+        .header("Authorization", authorization)
+        .body(Body::from(post_data))?;
+
+    http.request(req).await
+}
+
+pub(crate) fn parse_token(res: Response<BytesMut>) -> WickResult<AccessToken> {
+    read_epic_json(res, "Authentication Error with Response", 13)
+}
+
+pub(crate) async fn request_token(http: &HttpService, account_url: &str, authorization: &str, post_data: String) -> WickResult<AccessToken> {
+    let res = post_token(http, account_url, authorization, post_data).await?;
+    parse_token(res)
+}
+
+pub struct TokenManager {
+    provider: Box<dyn AuthProvider>,
+    account_url: String,
+    cache: Option<TokenCache>,
+    token: Mutex<Option<Arc<AccessToken>>>,
+}
+
+impl TokenManager {
+    pub fn new(provider: Box<dyn AuthProvider>) -> Self {
+        Self {
+            provider,
+            account_url: ACCOUNT_URL.to_owned(),
+            cache: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    // Points token requests at a different account service, such as a local stand-in
+    pub fn with_account_url(mut self, account_url: &str) -> Self {
+        self.account_url = account_url.to_owned();
+        self
+    }
 
-    let json_result = http.post_url_string(req).await?;
-    match serde_json::from_str(&json_result) {
-        Ok(res) => Ok(res),
-        Err(_) => Err(WickError::new_str(format!("Authentication Error with Response: {}", &json_result[..std::cmp::min(200, json_result.len())]), 13))
+    // Shares tokens with other processes through a file. The file holds a live credential, so keep it private.
+    pub fn with_cache_file<P>(mut self, path: P) -> Self where P: AsRef<Path> {
+        self.cache = Some(TokenCache::new(path));
+        self
     }
-}
\ No newline at end of file
+
+    pub async fn get_token(&self, http: &HttpService) -> WickResult<Option<Arc<AccessToken>>> {
+        // Holding the lock while fetching means concurrent callers wait on a single refresh
+        let mut token = self.token.lock().await;
+        if let Some(current) = token.as_ref() {
+            if !current.needs_refresh() {
+                return Ok(Some(Arc::clone(current)));
+            }
+        }
+
+        let fresh = match &self.cache {
+            Some(cache) => self.fetch_cached(http, cache).await?,
+            None => self.provider.fetch_token(http, &self.account_url).await?,
+        };
+        let fresh = fresh.map(Arc::new);
+        *token = fresh.clone();
+        Ok(fresh)
+    }
+
+    async fn fetch_cached(&self, http: &HttpService, cache: &TokenCache) -> WickResult<Option<AccessToken>> {
+        let lock = cache.lock().await?;
+        if let Some(cached) = cache.read(&lock) {
+            if !cached.needs_refresh() {
+                return Ok(Some(cached));
+            }
+        }
+
+        let fresh = self.provider.fetch_token(http, &self.account_url).await?;
+        if let Some(fresh) = &fresh {
+            cache.write(&lock, fresh)?;
+        }
+        Ok(fresh)
+    }
+
+    // Drops the cached token, unless someone else has already replaced it.
+    pub async fn invalidate(&self, stale: &AccessToken) -> WickResult<()> {
+        let mut token = self.token.lock().await;
+        let is_stale = match token.as_ref() {
+            Some(current) => current.access_token == stale.access_token,
+            None => false,
+        };
+        if is_stale {
+            *token = None;
+        }
+
+        if let Some(cache) = &self.cache {
+            let lock = cache.lock().await?;
+            let is_stale = match cache.read(&lock) {
+                Some(cached) => cached.access_token == stale.access_token,
+                None => false,
+            };
+            if is_stale {
+                cache.remove(&lock)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Builds a request with the current token and sends it, retrying once with a fresh token on a 401.
+    pub async fn send<F>(&self, http: &HttpService, build: F) -> WickResult<Response<BytesMut>> where F: Fn(http::request::Builder) -> WickResult<Request<Body>> {
+        let token = self.get_token(http).await?;
+        let res = http.request(build(authorize(&token))?).await?;
+        let token = match token {
+            Some(token) if res.status() == StatusCode::UNAUTHORIZED => token,
+            _ => return Ok(res),
+        };
+
+        self.invalidate(&token).await?;
+        let token = self.get_token(http).await?;
+        http.request(build(authorize(&token))?).await
+    }
+}
+
+impl Default for TokenManager {
+    fn default() -> Self {
+        Self::new(Box::new(ClientCredentials::default()))
+    }
+}
+
+fn authorize(token: &Option<Arc<AccessToken>>) -> http::request::Builder {
+    let builder = Request::builder();
+    match token {
+        Some(token) => builder.header("Authorization", "bearer ".to_owned() + token.get_access_token()),
+        None => builder,
+    }
+}
@@ -0,0 +1,63 @@
+use wickdl::{ServiceState, WickResult};
+
+const USAGE: &'static str = "usage: wickdl-verify <install dir> [--archive <manifest archive dir>] [--build <build version>]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+// Flags always take a value, running into the next flag or the end counts as missing it
+fn flag_value<I>(args: &mut I) -> String where I: Iterator<Item = String> {
+    match args.next() {
+        Some(value) if !value.starts_with('-') => value,
+        _ => usage(),
+    }
+}
+
+async fn run(args: Vec<String>) -> WickResult<bool> {
+    let mut install_dir = None;
+    let mut archive_dir = None;
+    let mut build_version = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--archive" => archive_dir = Some(flag_value(&mut args)),
+            "--build" => build_version = Some(flag_value(&mut args)),
+            _ if arg.starts_with('-') || install_dir.is_some() => usage(),
+            _ => install_dir = Some(arg),
+        }
+    }
+    let install_dir = match install_dir {
+        Some(dir) => dir,
+        None => usage(),
+    };
+
+    // Without an archive this checks against whatever the launcher says is current
+    let builder = ServiceState::builder();
+    let builder = match (archive_dir, build_version) {
+        (Some(archive), Some(build)) => builder.offline_build(archive, &build),
+        (Some(archive), None) => builder.offline(archive),
+        (None, None) => builder,
+        // A specific build can only come out of an archive
+        (None, Some(_)) => usage(),
+    };
+    let state = builder.build().await?;
+
+    let report = state.verify_install(&install_dir).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(report.is_ok())
+}
+
+#[tokio::main]
+async fn main() {
+    match run(std::env::args().skip(1).collect()).await {
+        Ok(true) => {},
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{ServiceState, WickResult};
+use crate::auth::{AuthProvider, ClientCredentials, TokenManager, ACCOUNT_URL};
+use crate::manifest::{self, ManifestQuery, LAUNCHER_URL};
+use crate::archive::ManifestArchive;
+use crate::filter::FileFilter;
+use crate::keys::KeyRing;
+use crate::http::HttpService;
+use crate::chunks::REQUEST_COUNT;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Where the manifests for the state come from
+enum Source {
+    Launcher,
+    Archive(ManifestArchive, Option<String>),
+    Manifests(String, Vec<u8>),
+}
+
+pub struct ServiceStateBuilder {
+    http: Option<Arc<HttpService>>,
+    auth: Box<dyn AuthProvider>,
+    token_cache: Option<PathBuf>,
+    account_url: String,
+    launcher_url: String,
+    query: ManifestQuery,
+    archive: Option<ManifestArchive>,
+    source: Source,
+    filter: FileFilter,
+    request_count: usize,
+    keys: KeyRing,
+}
+
+impl ServiceStateBuilder {
+    pub fn new() -> Self {
+        Self {
+            http: None,
+            auth: Box::new(ClientCredentials::default()),
+            token_cache: None,
+            account_url: ACCOUNT_URL.to_owned(),
+            launcher_url: LAUNCHER_URL.to_owned(),
+            query: ManifestQuery::default(),
+            archive: None,
+            source: Source::Launcher,
+            filter: FileFilter::default(),
+            request_count: REQUEST_COUNT,
+            keys: KeyRing::new(),
+        }
+    }
+
+    // Share one connection pool between several states
+    pub fn http(mut self, http: Arc<HttpService>) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    pub fn auth<P>(mut self, provider: P) -> Self where P: AuthProvider + 'static {
+        self.auth = Box::new(provider);
+        self
+    }
+
+    pub fn token_cache<P>(mut self, path: P) -> Self where P: Into<PathBuf> {
+        self.token_cache = Some(path.into());
+        self
+    }
+
+    // Base of the account service, without the trailing /oauth/token
+    pub fn account_url(mut self, account_url: &str) -> Self {
+        self.account_url = account_url.to_owned();
+        self
+    }
+
+    // Base of the launcher service, without the trailing /assets/...
+    pub fn launcher_url(mut self, launcher_url: &str) -> Self {
+        self.launcher_url = launcher_url.to_owned();
+        self
+    }
+
+    pub fn query(mut self, query: ManifestQuery) -> Self {
+        self.query = query;
+        self
+    }
+
+    // Keep a copy of every manifest fetched under this directory
+    pub fn archive_dir<P>(mut self, dir: P) -> Self where P: AsRef<Path> {
+        self.archive = Some(ManifestArchive::new(dir));
+        self
+    }
+
+    // Load the most recent build from a manifest archive instead of asking the launcher
+    pub fn offline<P>(mut self, dir: P) -> Self where P: AsRef<Path> {
+        self.source = Source::Archive(ManifestArchive::new(dir), None);
+        self
+    }
+
+    // Like offline, but for a specific build in the archive
+    pub fn offline_build<P>(mut self, dir: P, build_version: &str) -> Self where P: AsRef<Path> {
+        self.source = Source::Archive(ManifestArchive::new(dir), Some(build_version.to_owned()));
+        self
+    }
+
+    // Use manifests already in hand instead of asking the launcher. Chunks are still downloaded as normal.
+    pub fn manifests(mut self, app_manifest: &str, chunk_manifest: &[u8]) -> Self {
+        self.source = Source::Manifests(app_manifest.to_owned(), chunk_manifest.to_vec());
+        self
+    }
+
+    // Which files download_file and get_utoc can see, by default just the Fortnite IoStore containers
+    pub fn file_filter(mut self, filter: FileFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // How many chunk requests a single download keeps in flight
+    pub fn request_count(mut self, request_count: usize) -> Self {
+        self.request_count = std::cmp::max(request_count, 1);
+        self
+    }
+
+    // Keys for any encrypted containers get_utoc is asked to open
+    pub fn keys(mut self, keys: KeyRing) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub async fn build(self) -> WickResult<ServiceState> {
+        let http = match self.http {
+            Some(http) => http,
+            None => Arc::new(HttpService::new()),
+        };
+        let mut tokens = TokenManager::new(self.auth).with_account_url(&self.account_url);
+        if let Some(path) = self.token_cache {
+            tokens = tokens.with_cache_file(path);
+        }
+
+        let mut state = match &self.source {
+            Source::Launcher => {
+                ServiceState::connect(http, tokens, &self.query, &self.launcher_url, self.archive.as_ref(), &self.filter).await?
+            },
+            Source::Archive(archive, build_version) => {
+                ServiceState::from_archive(http, archive, build_version.as_deref(), &self.filter).await?
+            },
+            Source::Manifests(app_manifest, chunk_manifest) => {
+                let app_manifest = manifest::create_app_manifest(app_manifest)?;
+                ServiceState::from_parts(http, tokens, app_manifest, chunk_manifest, &self.filter)?
+            },
+        };
+        state.request_count = self.request_count;
+        state.keys = self.keys;
+
+        Ok(state)
+    }
+}
+
+impl Default for ServiceStateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,15 +1,17 @@
 use crate::err::{WickResult, make_err};
 use crate::http::HttpService;
-use crate::manifest::AppManifest;
+use crate::distribution::DistributionPool;
 use crate::spool::Spool;
 use john_wick_parse::manifest::{Manifest, FFileManifest, FChunkPart};
 use std::convert::AsRef;
 use std::io::{Cursor, Read, Seek, SeekFrom, Result as IOResult};
 use byteorder::{LittleEndian, ReadBytesExt};
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncWriteExt, AsyncSeekExt, ReadBuf};
 use futures::{join, FutureExt};
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use tokio::task::{JoinError, JoinHandle};
 use futures::channel::mpsc;
 use flate2::bufread::ZlibDecoder;
 
@@ -65,33 +67,8 @@ struct Chunk {
 
 impl Chunk {
     fn new<T>(data: T, chunk: &ChunkDownload) -> WickResult<Self> where T: AsRef<[u8]> {
-        let mut cursor = Cursor::new(data);
-        let _magic = cursor.read_u32::<LittleEndian>()?;
-        let header = ChunkHeader {
-            version: cursor.read_u32::<LittleEndian>()?,
-            size: cursor.read_u32::<LittleEndian>()?,
-            data_size: cursor.read_u32::<LittleEndian>()?,
-            guid: ChunkGuid::new(&mut cursor)?,
-            hash: cursor.read_u64::<LittleEndian>()?,
-            stored: cursor.read_u8()?,
-            sha: ChunkSha::new(&mut cursor)?,
-            hash_type: cursor.read_u8()?,
-        };
-
-        cursor.seek(SeekFrom::Start(header.size as u64))?;
-        let mut data = vec![0u8; header.data_size as usize];
-        cursor.read_exact(&mut data)?;
-
-        if header.stored & 0x01 == 1 {
-            let mut decompressed_data = Vec::new();
-            let mut decompressor = ZlibDecoder::new(data.as_slice());
-            decompressor.read_to_end(&mut decompressed_data)?;
-            let chunk_size = chunk.length as usize;
-            let chunk_offset = chunk.offset as usize;
-            let mut final_data = vec![0u8; chunk_size];
-            final_data.copy_from_slice(&decompressed_data[chunk_offset..(chunk_offset + chunk_size)]);
-            data = final_data;
-        }
+        let (header, data) = decode_chunk(data)?;
+        let data = slice_chunk(&data, chunk)?.to_vec();
 
         Ok(Self {
             header, data
@@ -99,13 +76,51 @@ impl Chunk {
     }
 }
 
+// The whole decompressed chunk, for when several parts are cut from it
+fn decode_chunk<T>(data: T) -> WickResult<(ChunkHeader, Vec<u8>)> where T: AsRef<[u8]> {
+    let mut cursor = Cursor::new(data);
+    let _magic = cursor.read_u32::<LittleEndian>()?;
+    let header = ChunkHeader {
+        version: cursor.read_u32::<LittleEndian>()?,
+        size: cursor.read_u32::<LittleEndian>()?,
+        data_size: cursor.read_u32::<LittleEndian>()?,
+        guid: ChunkGuid::new(&mut cursor)?,
+        hash: cursor.read_u64::<LittleEndian>()?,
+        stored: cursor.read_u8()?,
+        sha: ChunkSha::new(&mut cursor)?,
+        hash_type: cursor.read_u8()?,
+    };
+
+    cursor.seek(SeekFrom::Start(header.size as u64))?;
+    let mut data = vec![0u8; header.data_size as usize];
+    cursor.read_exact(&mut data)?;
+
+    if header.stored & 0x01 == 1 {
+        let mut decompressed_data = Vec::new();
+        let mut decompressor = ZlibDecoder::new(data.as_slice());
+        decompressor.read_to_end(&mut decompressed_data)?;
+        data = decompressed_data;
+    }
+
+    Ok((header, data))
+}
+
+fn slice_chunk<'a>(data: &'a [u8], chunk: &ChunkDownload) -> WickResult<&'a [u8]> {
+    let chunk_offset = chunk.offset as usize;
+    let chunk_end = chunk_offset + chunk.length as usize;
+    if chunk_end > data.len() {
+        return make_err("Chunk part is out of range");
+    }
+    Ok(&data[chunk_offset..chunk_end])
+}
+
 #[derive(Debug, Clone)]
-struct ChunkDownload {
-    position: u64,
-    length: u32,
-    url: String,
-    offset: u32,
-    index: usize,
+pub(crate) struct ChunkDownload {
+    pub(crate) position: u64,
+    pub(crate) length: u32,
+    pub(crate) path: String,
+    pub(crate) offset: u32,
+    pub(crate) index: usize,
 }
 
 type ChunkData = (ChunkDownload, Chunk);
@@ -120,28 +135,46 @@ async fn write_chunks(mut receiver: mpsc::UnboundedReceiver<ChunkData>, filesize
     Ok(())
 }
 
-async fn download_chunk(http: Arc<HttpService>, chunk: ChunkDownload) -> WickResult<ChunkData> {
-    let data = http.get_url(&chunk.url).await?;
-    let chunk_data = Chunk::new(data, &chunk)?;
+async fn download_chunk(http: Arc<HttpService>, pool: Arc<DistributionPool>, chunk: ChunkDownload) -> WickResult<ChunkData> {
+    let chunk_data = pool.fetch(&http, &chunk.path, |data| Chunk::new(data, &chunk)).await?;
     Ok((chunk, chunk_data))
 }
 
-async fn send_chunk(http: Arc<HttpService>, chunk: ChunkDownload, sender: mpsc::UnboundedSender<ChunkData>) -> WickResult<()> {
-    let data = download_chunk(http.clone(), chunk).await?;
+async fn send_chunk(http: Arc<HttpService>, pool: Arc<DistributionPool>, chunk: ChunkDownload, sender: mpsc::UnboundedSender<ChunkData>) -> WickResult<()> {
+    let data = download_chunk(http, pool, chunk).await?;
     sender.unbounded_send(data)?;
     Ok(())
 }
 
-const REQUEST_COUNT: usize = 20;
+pub const REQUEST_COUNT: usize = 20;
 
-const DOWNLOAD_BASE: &'static str = "Builds/Fortnite/CloudDir/ChunksV4/";
+// Everything a download needs to know about where a build's chunks come from
+#[derive(Clone)]
+pub(crate) struct ChunkSource {
+    pub(crate) http: Arc<HttpService>,
+    pub(crate) pool: Arc<DistributionPool>,
+    pub(crate) request_count: usize,
+    // Where the chunks live on the distributions, ending in a slash
+    pub(crate) chunk_dir: String,
+}
 
-fn make_chunk_url(manifest: &Manifest, chunk: &FChunkPart) -> WickResult<String> {
+// The chunk directory under the cloud dir moved every time the chunk format changed
+pub(crate) fn chunk_dir(cloud_dir: &str, feature_level: i32) -> String {
+    let subdir = match feature_level {
+        v if v < 3 => "Chunks",
+        v if v < 6 => "ChunksV2",
+        v if v < 15 => "ChunksV3",
+        _ => "ChunksV4",
+    };
+    format!("{}{}/", cloud_dir, subdir)
+}
+
+fn make_chunk_url(chunk_dir: &str, manifest: &Manifest, chunk: &FChunkPart) -> WickResult<String> {
     let chunk_info = match manifest.get_chunks().iter().find(|v| v.guid == chunk.guid) {
         Some(c) => c,
         None => return make_err("Could not find chunk hash"),
     };
-    let mut url = DOWNLOAD_BASE.to_owned();
+    let mut url = chunk_dir.to_owned();
     url += &format!("{:02}", chunk_info.group_number);
     url += "/";
     url += &format!("{:016X}", chunk_info.hash);
@@ -152,32 +185,40 @@ fn make_chunk_url(manifest: &Manifest, chunk: &FChunkPart) -> WickResult<String>
     Ok(url)
 }
 
-pub async fn download_file(http: Arc<HttpService>, manifest: &Manifest, app: &AppManifest, file: &FFileManifest, target: &str) -> WickResult<()> {
-    let distributions = app.get_distributions()?;
+// Lays the file's chunk parts end to end
+pub(crate) fn plan_downloads(chunk_dir: &str, manifest: &Manifest, file: &FFileManifest) -> WickResult<Vec<ChunkDownload>> {
     let mut downloads = Vec::new();
     let mut position = 0;
-    let mut i = 0;
-    for chunk in &file.chunk_parts {
+    for (i, chunk) in file.chunk_parts.iter().enumerate() {
         let download = ChunkDownload {
             position,
             length: chunk.size,
             offset: chunk.offset,
-            url: distributions[i % distributions.len()].to_owned() + &make_chunk_url(manifest, &chunk)?,
+            path: make_chunk_url(chunk_dir, manifest, &chunk)?,
             index: i,
         };
         downloads.push(download);
         position += chunk.size as u64;
-        i += 1;
     }
 
+    Ok(downloads)
+}
+
+pub(crate) async fn download_file(source: &ChunkSource, manifest: &Manifest, file: &FFileManifest, target: &str) -> WickResult<()> {
+    let downloads = plan_downloads(&source.chunk_dir, manifest, file)?;
+    let position = match downloads.last() {
+        Some(last) => last.position + last.length as u64,
+        None => 0,
+    };
+
     let (file_sender, file_receiver) = mpsc::unbounded::<ChunkData>();
     let chunk_downloads = downloads.into_iter().map(|v| {
-        send_chunk(http.clone(), v, file_sender.clone())
+        send_chunk(source.http.clone(), source.pool.clone(), v, file_sender.clone())
     }).collect();
 
     let (r1, r2) = join!(
         write_chunks(file_receiver, position, target),
-        Spool::build(chunk_downloads, REQUEST_COUNT).then(|_x| async move {
+        Spool::build(chunk_downloads, source.request_count).then(|_x| async move {
             file_sender.close_channel();
             Ok(()) as WickResult<()>
         })
@@ -188,25 +229,94 @@ pub async fn download_file(http: Arc<HttpService>, manifest: &Manifest, app: &Ap
     Ok(())
 }
 
-pub fn make_reader(http: Arc<HttpService>, manifest: &Manifest, app: &AppManifest, file: &FFileManifest) -> WickResult<ChunkReader> {
-    let distributions = app.get_distributions()?;
-    let mut downloads = Vec::new();
-    let mut position = 0;
-    let mut i = 0;
-    for chunk in &file.chunk_parts {
-        let download = ChunkDownload {
-            position,
-            length: chunk.size,
-            offset: chunk.offset,
-            url: distributions[i % distributions.len()].to_owned() + &make_chunk_url(manifest, &chunk)?,
-            index: i,
-        };
-        downloads.push(download);
-        position += chunk.size as u64;
-        i += 1;
+// Files that share chunks only download them once, and every request counts against the one limit.
+// on_complete gets the filename and target of each file once all of its parts are written.
+pub(crate) async fn download_files<F>(source: &ChunkSource, manifest: &Manifest, files: &[(&FFileManifest, &str)], mut on_complete: F) -> WickResult<()> where F: FnMut(&str, &str) {
+    let mut parts = Vec::new();
+    for (i, (file, target)) in files.iter().enumerate() {
+        let downloads = plan_downloads(&source.chunk_dir, manifest, file)?;
+        let size = downloads.last().map(|v| v.position + v.length as u64).unwrap_or(0);
+        File::create(target).await?.set_len(size).await?;
+        if downloads.is_empty() {
+            on_complete(&file.filename, target);
+        }
+        parts.extend(downloads.into_iter().map(|v| (i, v)));
     }
 
-    Ok(ChunkReader::new(http.clone(), downloads))
+    let targets: Vec<&str> = files.iter().map(|v| v.1).collect();
+    download_parts(source, parts, &targets, |i| on_complete(&files[i].0.filename, files[i].1)).await
+}
+
+// Fetches run as their own tasks so they keep going while parts are written. Holding them in this
+// means an error or a dropped download stops the rest instead of leaving them running.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Writes each part into targets[i] at its position. The targets have to exist already, nothing is truncated.
+// on_complete gets the index of each target once all of its parts are written.
+pub(crate) async fn download_parts<F>(source: &ChunkSource, parts: Vec<(usize, ChunkDownload)>, targets: &[&str], mut on_complete: F) -> WickResult<()> where F: FnMut(usize) {
+    let mut chunks: Vec<(String, Vec<(usize, ChunkDownload)>)> = Vec::new();
+    let mut chunk_index = HashMap::new();
+    let mut remaining = vec![0usize; targets.len()];
+    for (i, download) in parts {
+        remaining[i] += 1;
+        let idx = *chunk_index.entry(download.path.clone()).or_insert_with(|| {
+            chunks.push((download.path.clone(), Vec::new()));
+            chunks.len() - 1
+        });
+        chunks[idx].1.push((i, download));
+    }
+
+    let mut fetches = stream::iter(chunks.into_iter().map(|(path, parts)| {
+        let http = source.http.clone();
+        let pool = source.pool.clone();
+        AbortOnDrop(tokio::spawn(async move {
+            let data = pool.fetch(&http, &path, |data| decode_chunk(data).map(|v| v.1)).await?;
+            Ok((data, parts)) as WickResult<(Vec<u8>, Vec<(usize, ChunkDownload)>)>
+        }))
+    })).buffer_unordered(source.request_count);
+
+    let mut open_files = HashMap::new();
+    while let Some(res) = fetches.next().await {
+        let (data, parts) = res??;
+        for (i, part) in parts {
+            if !open_files.contains_key(&i) {
+                let handle = OpenOptions::new().write(true).open(targets[i]).await?;
+                open_files.insert(i, handle);
+            }
+            let handle = open_files.get_mut(&i).unwrap();
+            handle.seek(SeekFrom::Start(part.position)).await?;
+            handle.write_all(slice_chunk(&data, &part)?).await?;
+
+            remaining[i] -= 1;
+            if remaining[i] == 0 {
+                let mut handle = open_files.remove(&i).unwrap();
+                handle.flush().await?;
+                on_complete(i);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn make_reader(source: &ChunkSource, manifest: &Manifest, file: &FFileManifest) -> WickResult<ChunkReader> {
+    let downloads = plan_downloads(&source.chunk_dir, manifest, file)?;
+
+    Ok(ChunkReader::new(source.http.clone(), source.pool.clone(), downloads))
 }
 
 use std::pin::Pin;
@@ -222,6 +332,7 @@ enum ChunkReaderState {
 
 pub struct ChunkReader {
     http: Arc<HttpService>,
+    pool: Arc<DistributionPool>,
     chunks: Arc<Vec<ChunkDownload>>,
     position: u64,
     current_chunk: usize,
@@ -230,7 +341,7 @@ pub struct ChunkReader {
 }
 
 impl ChunkReader {
-    fn new(http: Arc<HttpService>, chunks: Vec<ChunkDownload>) -> Self {
+    fn new(http: Arc<HttpService>, pool: Arc<DistributionPool>, chunks: Vec<ChunkDownload>) -> Self {
         if chunks.len() <= 0 {
             panic!("Cannot read an empty chunk list.");
         }
@@ -238,9 +349,10 @@ impl ChunkReader {
             let last_chunk = chunks.last().unwrap();
             last_chunk.position + last_chunk.length as u64
         };
-        let first_resolve = download_chunk(http.clone(), chunks[0].clone());
+        let first_resolve = download_chunk(http.clone(), pool.clone(), chunks[0].clone());
         Self {
             http: http.clone(),
+            pool,
             chunks: Arc::new(chunks),
             position: 0,
             current_chunk: 0,
@@ -250,9 +362,10 @@ impl ChunkReader {
     }
 
     pub fn reset(&self) -> Self {
-        let first_resolve = download_chunk(self.http.clone(), self.chunks[0].clone());
+        let first_resolve = download_chunk(self.http.clone(), self.pool.clone(), self.chunks[0].clone());
         Self {
             http: Arc::clone(&self.http),
+            pool: Arc::clone(&self.pool),
             chunks: Arc::clone(&self.chunks),
             position: 0,
             current_chunk: 0,
@@ -260,6 +373,10 @@ impl ChunkReader {
             total_size: self.total_size,
         }
     }
+
+    pub fn get_total_size(&self) -> u64 {
+        self.total_size
+    }
 }
 
 impl Seek for ChunkReader {
@@ -271,7 +388,7 @@ impl Seek for ChunkReader {
         };
         let chunk = self.chunks.iter().find(|&i| fpos >= i.position && (i.position + i.length as u64) > fpos).expect("No chunk found for position");
         if self.current_chunk != chunk.index {
-            self.state = ChunkReaderState::Resolving(Box::pin(download_chunk(self.http.clone(), chunk.clone())));
+            self.state = ChunkReaderState::Resolving(Box::pin(download_chunk(self.http.clone(), self.pool.clone(), chunk.clone())));
         }
 
         self.position = fpos;
@@ -305,7 +422,7 @@ impl AsyncRead for ChunkReader {
                         if this.current_chunk >= this.chunks.len() {
                             return Poll::Ready(Ok(())); // Nothing left to read
                         }
-                        let resolve = download_chunk(this.http.clone(), this.chunks[this.current_chunk].clone());
+                        let resolve = download_chunk(this.http.clone(), this.pool.clone(), this.chunks[this.current_chunk].clone());
                         this.state = ChunkReaderState::Resolving(Box::pin(resolve));
                     }
                 },
@@ -0,0 +1,91 @@
+use crate::hash::{guid_key, sha_hex};
+use crate::info::chunk_sizes;
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+    new_chunks: Vec<String>,
+    download_size: u64,
+}
+
+fn same_contents(old: &FFileManifest, new: &FFileManifest) -> bool {
+    if sha_hex(&old.file_hash) != sha_hex(&new.file_hash) || old.chunk_parts.len() != new.chunk_parts.len() {
+        return false;
+    }
+    old.chunk_parts.iter().zip(new.chunk_parts.iter()).all(|(a, b)| {
+        guid_key(&a.guid) == guid_key(&b.guid) && a.offset == b.offset && a.size == b.size
+    })
+}
+
+impl ManifestDiff {
+    pub fn between(old: &Manifest, new: &Manifest) -> Self {
+        let old_files: HashMap<&str, &FFileManifest> = old.get_files().iter().map(|v| (v.filename.as_str(), v)).collect();
+        let new_files: HashMap<&str, &FFileManifest> = new.get_files().iter().map(|v| (v.filename.as_str(), v)).collect();
+        let old_chunks: HashSet<String> = old.get_chunks().iter().map(|v| guid_key(&v.guid)).collect();
+        let chunk_sizes = chunk_sizes(new);
+
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
+        let mut new_chunks = Vec::new();
+        let mut seen_chunks = HashSet::new();
+        for file in new.get_files() {
+            match old_files.get(file.filename.as_str()) {
+                Some(old_file) if same_contents(old_file, file) => continue,
+                Some(_) => modified.push(file.filename.clone()),
+                None => added.push(file.filename.clone()),
+            }
+
+            for part in &file.chunk_parts {
+                let guid = guid_key(&part.guid);
+                if !old_chunks.contains(&guid) && seen_chunks.insert(guid.clone()) {
+                    new_chunks.push(guid);
+                }
+            }
+        }
+
+        let removed = old.get_files().iter()
+            .filter(|v| !new_files.contains_key(v.filename.as_str()))
+            .map(|v| v.filename.clone())
+            .collect();
+        let download_size = new_chunks.iter().map(|v| chunk_sizes.get(v).cloned().unwrap_or(0)).sum();
+
+        Self {
+            added,
+            removed,
+            modified,
+            new_chunks,
+            download_size,
+        }
+    }
+
+    pub fn get_added(&self) -> &Vec<String> {
+        &self.added
+    }
+
+    pub fn get_removed(&self) -> &Vec<String> {
+        &self.removed
+    }
+
+    pub fn get_modified(&self) -> &Vec<String> {
+        &self.modified
+    }
+
+    // GUIDs of chunks the new build needs that the old one didn't have
+    pub fn get_new_chunks(&self) -> &Vec<String> {
+        &self.new_chunks
+    }
+
+    // Compressed size of the new chunks, as they'd come off the CDN
+    pub fn get_download_size(&self) -> u64 {
+        self.download_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
@@ -0,0 +1,333 @@
+use crate::err::{WickError, WickResult};
+use crate::http::HttpService;
+use bytes::BytesMut;
+use hyper::{Request, Response, Body, StatusCode};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// How long a host sits out after an error
+const QUARANTINE_TIME: Duration = Duration::from_secs(30);
+// Hosts tried for a single request before giving up
+const MAX_ATTEMPTS: usize = 3;
+// Weight given to the newest sample in the moving averages
+const SMOOTHING: f64 = 0.2;
+// Hosts this close to the best score share the load
+const SCORE_SPREAD: f64 = 1.5;
+// Size used to turn throughput into an expected time, about one chunk
+const EXPECTED_SIZE: f64 = 1048576.0;
+
+struct HostStats {
+    url: String,
+    requests: u64,
+    failures: u64,
+    latency: Option<f64>,
+    throughput: Option<f64>,
+    quarantined_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostStatus {
+    pub url: String,
+    pub requests: u64,
+    pub failures: u64,
+    pub latency_secs: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+    pub quarantined: bool,
+}
+
+fn smooth(current: Option<f64>, sample: f64) -> Option<f64> {
+    match current {
+        Some(v) => Some(v * (1.0 - SMOOTHING) + sample * SMOOTHING),
+        None => Some(sample),
+    }
+}
+
+impl HostStats {
+    fn is_quarantined(&self, now: Instant) -> bool {
+        match self.quarantined_until {
+            Some(until) => until > now,
+            None => false,
+        }
+    }
+
+    // Rough seconds to fetch a chunk, lower is better. Hosts we know nothing about score 0 so they get tried.
+    fn score(&self) -> f64 {
+        let latency = self.latency.unwrap_or(0.0);
+        let transfer = match self.throughput {
+            Some(v) if v > 0.0 => EXPECTED_SIZE / v,
+            _ => 0.0,
+        };
+        let failure_rate = if self.requests > 0 { self.failures as f64 / self.requests as f64 } else { 0.0 };
+        (latency + transfer) * (1.0 + 4.0 * failure_rate)
+    }
+}
+
+// The CDN hosts for a build, ranked by how well they've been behaving
+pub struct DistributionPool {
+    hosts: Mutex<Vec<HostStats>>,
+    next: AtomicUsize,
+}
+
+impl DistributionPool {
+    pub fn new(distributions: Vec<String>) -> Self {
+        let hosts = distributions.into_iter().map(|url| HostStats {
+            url,
+            requests: 0,
+            failures: 0,
+            latency: None,
+            throughput: None,
+            quarantined_until: None,
+        }).collect();
+
+        Self {
+            hosts: Mutex::new(hosts),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn pick(&self, exclude: &[usize]) -> Option<(usize, String)> {
+        let hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        let available: Vec<usize> = (0..hosts.len()).filter(|v| !exclude.contains(v)).collect();
+        if available.is_empty() {
+            return None;
+        }
+
+        let healthy: Vec<usize> = available.iter().cloned().filter(|v| !hosts[*v].is_quarantined(now)).collect();
+        if healthy.is_empty() {
+            // Everything is quarantined, so go with whichever comes back soonest
+            let idx = *available.iter().min_by_key(|v| hosts[**v].quarantined_until).unwrap();
+            return Some((idx, hosts[idx].url.clone()));
+        }
+
+        let best = healthy.iter().map(|v| hosts[*v].score()).fold(f64::MAX, f64::min);
+        let candidates: Vec<usize> = healthy.into_iter().filter(|v| hosts[*v].score() <= best * SCORE_SPREAD).collect();
+        let idx = candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()];
+        Some((idx, hosts[idx].url.clone()))
+    }
+
+    fn record_success(&self, idx: usize, elapsed: Duration, bytes: usize) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = &mut hosts[idx];
+        let secs = elapsed.as_secs_f64().max(0.001);
+        host.requests += 1;
+        host.latency = smooth(host.latency, secs);
+        host.throughput = smooth(host.throughput, bytes as f64 / secs);
+        host.quarantined_until = None;
+    }
+
+    fn record_failure(&self, idx: usize) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = &mut hosts[idx];
+        host.requests += 1;
+        host.failures += 1;
+        host.quarantined_until = Some(Instant::now() + QUARANTINE_TIME);
+    }
+
+    // Fetches a path from the best host available, moving on to the next one if the request fails
+    // or the host has trouble answering. Anything else is the same on every host, so it's returned as is.
+    pub async fn fetch<T, F>(&self, http: &HttpService, path: &str, parse: F) -> WickResult<T> where F: Fn(BytesMut) -> WickResult<T> {
+        let mut tried = Vec::new();
+        let mut last_err = None;
+        while tried.len() < MAX_ATTEMPTS {
+            let (idx, host) = match self.pick(&tried) {
+                Some(v) => v,
+                None => break,
+            };
+            tried.push(idx);
+
+            let url = host + path;
+            let start = Instant::now();
+            let res = match get(http, &url).await {
+                Ok(res) => res,
+                Err(e) => {
+                    self.record_failure(idx);
+                    last_err = Some(e);
+                    continue;
+                },
+            };
+
+            let status = res.status();
+            if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                self.record_failure(idx);
+                last_err = Some(status_err(status, &url));
+                continue;
+            }
+            if !status.is_success() {
+                return Err(status_err(status, &url));
+            }
+
+            let data = res.into_body();
+            let size = data.len();
+            let parsed = parse(data)?;
+            self.record_success(idx, start.elapsed(), size);
+            return Ok(parsed);
+        }
+
+        match last_err {
+            Some(e) => Err(e),
+            None => Err(WickError::new_str("No distributions available".to_owned(), 18)),
+        }
+    }
+
+    pub fn get_status(&self) -> Vec<HostStatus> {
+        let hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        hosts.iter().map(|v| HostStatus {
+            url: v.url.clone(),
+            requests: v.requests,
+            failures: v.failures,
+            latency_secs: v.latency,
+            bytes_per_sec: v.throughput,
+            quarantined: v.is_quarantined(now),
+        }).collect()
+    }
+}
+
+async fn get(http: &HttpService, url: &str) -> WickResult<Response<BytesMut>> {
+    let req = Request::builder()
+        .method("GET")
+        .uri(url)
+        .body(Body::empty())?;
+    http.request(req).await
+}
+
+fn status_err(status: StatusCode, url: &str) -> WickError {
+    WickError::new_str(format!("Distribution returned {} for {}", status, url), 18)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use tokio::net::TcpListener;
+
+    struct Host {
+        url: String,
+        server: TestServer,
+    }
+
+    impl Host {
+        fn hits(&self) -> usize {
+            self.server.get_requests().len()
+        }
+    }
+
+    // Answers the first `failures` requests with the failure status and everything after with the body
+    async fn serve(failures: usize, failure_status: u16, body: &'static str) -> Host {
+        let hits = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            if hits.fetch_add(1, Ordering::SeqCst) < failures {
+                TestResponse::new(failure_status, "")
+            } else {
+                TestResponse::new(200, body)
+            }
+        }).await;
+        Host {
+            url: format!("{}/", server.get_url()),
+            server,
+        }
+    }
+
+    // A port nothing is listening on
+    async fn unreachable() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/", listener.local_addr().unwrap())
+    }
+
+    async fn fetch(pool: &DistributionPool, http: &HttpService) -> WickResult<String> {
+        pool.fetch(http, "chunk", |data| Ok(String::from_utf8_lossy(&data).into_owned())).await
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_host() {
+        let http = HttpService::new();
+        let good = serve(0, 500, "chunk data").await;
+        let pool = DistributionPool::new(vec![unreachable().await, good.url.clone()]);
+
+        assert_eq!(fetch(&pool, &http).await.unwrap(), "chunk data");
+        assert_eq!(good.hits(), 1);
+        let status = pool.get_status();
+        assert!(status[0].quarantined);
+        assert_eq!((status[0].requests, status[0].failures), (1, 1));
+        assert!(!status[1].quarantined);
+        assert_eq!((status[1].requests, status[1].failures), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn quarantined_host_is_skipped_until_cooldown_ends() {
+        let http = HttpService::new();
+        let flaky = serve(1, 500, "from flaky").await;
+        let good = serve(0, 500, "from good").await;
+        let pool = DistributionPool::new(vec![flaky.url.clone(), good.url.clone()]);
+
+        assert_eq!(fetch(&pool, &http).await.unwrap(), "from good");
+        for _ in 0..3 {
+            assert_eq!(fetch(&pool, &http).await.unwrap(), "from good");
+        }
+        assert_eq!(flaky.hits(), 1);
+        assert_eq!(good.hits(), 4);
+
+        // Rather than wait out QUARANTINE_TIME
+        pool.hosts.lock().unwrap()[0].quarantined_until = Some(Instant::now() - Duration::from_secs(1));
+        assert!(!pool.get_status()[0].quarantined);
+
+        // It has no timings yet, so it scores best and gets tried straight away
+        assert_eq!(fetch(&pool, &http).await.unwrap(), "from flaky");
+        assert_eq!(flaky.hits(), 2);
+        assert_eq!(good.hits(), 4);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let http = HttpService::new();
+        let mut hosts = Vec::new();
+        for _ in 0..(MAX_ATTEMPTS + 1) {
+            hosts.push(serve(usize::MAX, 500, "").await);
+        }
+        let pool = DistributionPool::new(hosts.iter().map(|v| v.url.clone()).collect());
+
+        assert_eq!(fetch(&pool, &http).await.unwrap_err().get_code(), 18);
+        assert_eq!(hosts.iter().map(|v| v.hits()).sum::<usize>(), MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn throttled_host_is_quarantined() {
+        let http = HttpService::new();
+        let throttled = serve(1, 429, "from throttled").await;
+        let good = serve(0, 500, "from good").await;
+        let pool = DistributionPool::new(vec![throttled.url.clone(), good.url.clone()]);
+
+        assert_eq!(fetch(&pool, &http).await.unwrap(), "from good");
+        assert!(pool.get_status()[0].quarantined);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_returned_without_quarantine() {
+        let http = HttpService::new();
+        let missing = serve(usize::MAX, 404, "").await;
+        let good = serve(0, 500, "from good").await;
+        let pool = DistributionPool::new(vec![missing.url.clone(), good.url.clone()]);
+
+        assert_eq!(fetch(&pool, &http).await.unwrap_err().get_code(), 18);
+        assert_eq!((missing.hits(), good.hits()), (1, 0));
+        let status = pool.get_status();
+        assert!(!status[0].quarantined);
+        assert_eq!(status[0].failures, 0);
+    }
+
+    #[tokio::test]
+    async fn parse_errors_are_returned_without_quarantine() {
+        let http = HttpService::new();
+        let first = serve(0, 500, "not a chunk").await;
+        let second = serve(0, 500, "not a chunk").await;
+        let pool = DistributionPool::new(vec![first.url.clone(), second.url.clone()]);
+
+        let res: WickResult<()> = pool.fetch(&http, "chunk", |_| Err(WickError::new_str("Bad chunk".to_owned(), 15))).await;
+        assert_eq!(res.unwrap_err().get_code(), 15);
+        assert_eq!(first.hits() + second.hits(), 1);
+        assert!(pool.get_status().iter().all(|v| !v.quarantined && v.failures == 0));
+    }
+}
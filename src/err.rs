@@ -1,7 +1,78 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use hyper::Response;
+use bytes::BytesMut;
+use std::time::Duration;
+
 #[derive(Debug)]
 pub struct WickError {
     error: String,
     code: u32,
+    epic: Option<Box<EpicError>>,
+}
+
+// The error envelope returned by the account and launcher services
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EpicError {
+    error_code: String,
+    #[serde(default)]
+    error_message: String,
+    #[serde(default)]
+    numeric_error_code: i32,
+    #[serde(default)]
+    message_vars: Vec<String>,
+    #[serde(default)]
+    intent: String,
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EpicErrorKind {
+    InvalidCredentials,
+    Throttled(Option<Duration>),
+    Maintenance,
+    Other,
+}
+
+impl EpicError {
+    pub fn get_error_code(&self) -> &str {
+        &self.error_code
+    }
+
+    pub fn get_error_message(&self) -> &str {
+        &self.error_message
+    }
+
+    pub fn get_numeric_error_code(&self) -> i32 {
+        self.numeric_error_code
+    }
+
+    pub fn get_message_vars(&self) -> &Vec<String> {
+        &self.message_vars
+    }
+
+    pub fn get_intent(&self) -> &str {
+        &self.intent
+    }
+
+    pub fn get_kind(&self) -> EpicErrorKind {
+        let code = self.error_code.as_str();
+        if code.ends_with(".throttled") || self.numeric_error_code == 1041 {
+            // The wait is usually in the first message var, otherwise fall back to the Retry-After header
+            let retry_after = self.message_vars.first().and_then(|v| v.parse().ok()).or(self.retry_after);
+            return EpicErrorKind::Throttled(retry_after.map(Duration::from_secs));
+        }
+        if code.contains("maintenance") {
+            return EpicErrorKind::Maintenance;
+        }
+        if code.contains("invalid_client") || code.contains("invalid_grant") || code.contains("invalid_refresh_token") ||
+            code.contains("token_verification_failed") || (code.ends_with("_not_found") && code.contains(".oauth.")) {
+            return EpicErrorKind::InvalidCredentials;
+        }
+        EpicErrorKind::Other
+    }
 }
 
 pub type WickResult<T> = Result<T, WickError>;
@@ -11,6 +82,7 @@ impl WickError {
         WickError {
             error: error.to_owned(),
             code,
+            epic: None,
         }
     }
 
@@ -18,12 +90,25 @@ impl WickError {
         WickError {
             error,
             code,
+            epic: None,
+        }
+    }
+
+    pub(crate) fn from_epic(epic: EpicError, code: u32) -> Self {
+        WickError {
+            error: format!("{} ({})", epic.error_message, epic.error_code),
+            code,
+            epic: Some(Box::new(epic)),
         }
     }
 
     pub fn get_code(&self) -> u32 {
         self.code
     }
+
+    pub fn get_epic_error(&self) -> Option<&EpicError> {
+        self.epic.as_deref()
+    }
 }
 
 impl std::fmt::Display for WickError {
@@ -84,6 +169,12 @@ impl<T> From<futures::channel::mpsc::TrySendError<T>> for WickError {
     }
 }
 
+impl From<tokio::task::JoinError> for WickError {
+    fn from(_error: tokio::task::JoinError) -> Self {
+        Self::new("Background task error", 9)
+    }
+}
+
 impl From<john_wick_parse::assets::ParserError> for WickError {
     fn from(error: john_wick_parse::assets::ParserError) -> Self {
         Self::new_str(format!("Could not parse: {}", error), 8)
@@ -94,6 +185,32 @@ pub fn make_err<T>(msg: &str) -> Result<T, WickError> {
     Err(WickError::new(msg, 12))
 }
 
+// Reads a JSON response from one of the Epic services, turning their error envelope into an EpicError.
+pub(crate) fn read_epic_json<T>(res: Response<BytesMut>, context: &str, code: u32) -> WickResult<T> where T: DeserializeOwned {
+    let body = std::str::from_utf8(res.body())?;
+    if !res.status().is_success() {
+        if let Ok(mut epic) = serde_json::from_str::<EpicError>(body) {
+            epic.retry_after = match res.headers().get(hyper::header::RETRY_AFTER) {
+                Some(val) => val.to_str()?.parse().ok(),
+                None => None,
+            };
+            return Err(WickError::from_epic(epic, code));
+        }
+    }
+
+    match serde_json::from_str(body) {
+        Ok(res) => Ok(res),
+        Err(_) => Err(WickError::new_str(format!("{}: {}", context, &body[..std::cmp::min(200, body.len())]), code)),
+    }
+}
+
 // 13 - Authentication Error
 // 14 - App Manifest Read Error
-// 15 - Chunk Manifest Read Error
\ No newline at end of file
+// 15 - Chunk Manifest Read Error
+// 16 - Chunk Manifest Hash Mismatch
+// 17 - Account Service Used In Offline Mode
+// 18 - Distribution Error
+// 19 - Pak Read Error
+// 20 - Key Read Error
+// 21 - Missing Encryption Key
+// 22 - Install Error
\ No newline at end of file
@@ -0,0 +1,153 @@
+use john_wick_parse::manifest::FFileManifest;
+
+// Picks which files in a build a ServiceState works with. Each kind of rule that has been
+// given must match: any one pattern, any one extension, any one install tag, and every predicate.
+pub struct FileFilter {
+    patterns: Vec<String>,
+    extensions: Vec<String>,
+    install_tags: Vec<String>,
+    predicates: Vec<Box<dyn Fn(&FFileManifest) -> bool + Send + Sync>>,
+}
+
+// Glob match where * is any run of characters (including /) and ? is any one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last star swallow one more character and try again
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|v| *v == '*')
+}
+
+impl FileFilter {
+    // Matches every file
+    pub fn all() -> Self {
+        Self {
+            patterns: Vec::new(),
+            extensions: Vec::new(),
+            install_tags: Vec::new(),
+            predicates: Vec::new(),
+        }
+    }
+
+    pub fn pattern(mut self, pattern: &str) -> Self {
+        self.patterns.push(pattern.to_owned());
+        self
+    }
+
+    // Without the dot, compared case-insensitively
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.extensions.push(extension.trim_start_matches('.').to_lowercase());
+        self
+    }
+
+    pub fn install_tag(mut self, tag: &str) -> Self {
+        self.install_tags.push(tag.to_owned());
+        self
+    }
+
+    pub fn predicate<F>(mut self, predicate: F) -> Self where F: Fn(&FFileManifest) -> bool + Send + Sync + 'static {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    pub fn matches(&self, file: &FFileManifest) -> bool {
+        let filename = &file.filename;
+        if !self.patterns.is_empty() && !self.patterns.iter().any(|v| glob_match(v, filename)) {
+            return false;
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = match filename.rfind('.') {
+                Some(pos) => filename[(pos + 1)..].to_lowercase(),
+                None => return false,
+            };
+            if !self.extensions.contains(&extension) {
+                return false;
+            }
+        }
+
+        if !self.install_tags.is_empty() && !file.install_tags.iter().any(|v| self.install_tags.contains(v)) {
+            return false;
+        }
+
+        self.predicates.iter().all(|v| v(file))
+    }
+}
+
+// The Fortnite IoStore containers, which is all we used to look at
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self::all().pattern("Fortnite*").extension("utoc").extension("ucas")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_manifest::convert_json_manifest;
+    use john_wick_parse::manifest::Manifest;
+
+    // Files with no chunks, going through the JSON converter since that's the easy way to build them
+    fn files(names: &[&str]) -> Vec<FFileManifest> {
+        let list: Vec<String> = names.iter().map(|v| format!(r#"{{ "Filename": {:?}, "FileHash": "", "FileChunkParts": [] }}"#, v)).collect();
+        let json = format!(r#"{{ "ManifestFileVersion": "012000000000", "FileManifestList": [{}], "ChunkHashList": {{}} }}"#, list.join(","));
+        Manifest::from_buffer(&convert_json_manifest(json.as_bytes()).unwrap()).unwrap().get_files().clone()
+    }
+
+    #[test]
+    fn star_crosses_slashes() {
+        assert!(glob_match("Fortnite*", "FortniteGame/Content/Paks/global.utoc"));
+        assert!(glob_match("*/Paks/*.utoc", "FortniteGame/Content/Paks/global.utoc"));
+        assert!(glob_match("a*b*c", "a/x/b/y/c"));
+        assert!(!glob_match("*/Paks/*.utoc", "FortniteGame/Content/Paks/global.ucas"));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        assert!(glob_match("pakchunk?.pak", "pakchunk0.pak"));
+        assert!(glob_match("a?c", "a/c"));
+        assert!(!glob_match("pakchunk?.pak", "pakchunk10.pak"));
+        assert!(!glob_match("a?c", "ac"));
+    }
+
+    #[test]
+    fn trailing_star_matches_nothing_too() {
+        assert!(glob_match("Fortnite*", "Fortnite"));
+        assert!(glob_match("Fortnite**", "Fortnite"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("Fortnite*", "Fortnit"));
+    }
+
+    #[test]
+    fn empty_pattern_only_matches_empty() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(!glob_match("a", ""));
+    }
+
+    #[test]
+    fn default_filter_handles_short_names() {
+        let filter = FileFilter::default();
+        let names = ["a.pak", "a", "", ".utoc", "F.utoc", "Fortnite", "FortniteGame/Content/Paks/global.utoc", "FortniteGame/Content/Paks/global.ucas", "FortniteGame/Content/Paks/pakchunk0-WindowsClient.pak"];
+        let matched: Vec<String> = files(&names).into_iter().filter(|v| filter.matches(v)).map(|v| v.filename).collect();
+        assert_eq!(matched, vec!["FortniteGame/Content/Paks/global.utoc", "FortniteGame/Content/Paks/global.ucas"]);
+    }
+}
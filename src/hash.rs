@@ -0,0 +1,17 @@
+use sha1::{Sha1, Digest};
+use std::fmt::Display;
+
+// The parser's GUID and SHA types only promise Display, so everything that
+// needs to key or compare them goes through these.
+
+pub fn guid_key<T>(guid: &T) -> String where T: Display {
+    format!("{}", guid).to_uppercase()
+}
+
+pub fn sha_hex<T>(hash: &T) -> String where T: Display {
+    format!("{}", hash).to_lowercase()
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
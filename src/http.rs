@@ -1,5 +1,5 @@
 use crate::err::WickResult;
-use hyper::{Client, body::HttpBody as _, Request, Body};
+use hyper::{Client, body::HttpBody as _, Request, Response, Body};
 use hyper::client::connect::HttpConnector;
 use hyper_tls::HttpsConnector;
 use bytes::BytesMut;
@@ -18,7 +18,7 @@ impl HttpService {
         }
     }
 
-    async fn process_request(&self, mut response: http::response::Response<Body>) -> WickResult<BytesMut> {
+    async fn process_request(&self, response: &mut Response<Body>) -> WickResult<BytesMut> {
         let content_length: usize = match response.headers().get(hyper::header::CONTENT_LENGTH) {
             Some(val) => val.to_str()?.parse()?,
             None => 0,
@@ -33,8 +33,8 @@ impl HttpService {
     }
 
     pub async fn get_url(&self, url: &str) -> WickResult<BytesMut> {
-        let res = self.client.get(url.parse().unwrap()).await?;
-        self.process_request(res).await
+        let mut res = self.client.get(url.parse().unwrap()).await?;
+        self.process_request(&mut res).await
     }
 
     pub async fn get_url_string(&self, url: &str) -> WickResult<String> {
@@ -44,13 +44,39 @@ impl HttpService {
     }
 
     pub async fn post_url(&self, request: Request<Body>) -> WickResult<BytesMut> {
-        let res = self.client.request(request).await?;
-        self.process_request(res).await
+        let mut res = self.client.request(request).await?;
+        self.process_request(&mut res).await
     }
     
+    // Like post_url, but hands back the status and headers alongside the body
+    pub async fn request(&self, request: Request<Body>) -> WickResult<Response<BytesMut>> {
+        let mut res = self.client.request(request).await?;
+        let body = self.process_request(&mut res).await?;
+        let (parts, _) = res.into_parts();
+        Ok(Response::from_parts(parts, body))
+    }
+
     pub async fn post_url_string(&self, request: Request<Body>) -> WickResult<String> {
         let bytes = self.post_url(request).await?;
 
         Ok(std::str::from_utf8(&bytes)?.to_owned())
     }
-}
\ No newline at end of file
+}
+
+// Percent-encodes everything outside the unreserved set, for URL parts and form bodies
+pub(crate) fn url_encode(val: &str) -> String {
+    let mut result = String::with_capacity(val.len());
+    for byte in val.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => result.push(byte as char),
+            _ => result += &format!("%{:02X}", byte),
+        }
+    }
+    result
+}
+
+impl Default for HttpService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
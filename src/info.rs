@@ -0,0 +1,41 @@
+use crate::hash::{guid_key, sha_hex};
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+    pub install_tags: Vec<String>,
+    pub chunk_count: usize,
+    pub chunk_guids: Vec<String>,
+    // Compressed size of the distinct chunks, what actually comes off the CDN
+    pub download_size: u64,
+}
+
+impl FileInfo {
+    pub(crate) fn new(file: &FFileManifest, chunk_sizes: &HashMap<String, u64>) -> Self {
+        let mut seen = HashSet::new();
+        let chunk_guids: Vec<String> = file.chunk_parts.iter()
+            .map(|v| guid_key(&v.guid))
+            .filter(|v| seen.insert(v.clone()))
+            .collect();
+        let download_size = chunk_guids.iter().map(|v| chunk_sizes.get(v).cloned().unwrap_or(0)).sum();
+
+        Self {
+            filename: file.filename.clone(),
+            size: file.chunk_parts.iter().map(|v| v.size as u64).sum(),
+            hash: sha_hex(&file.file_hash),
+            install_tags: file.install_tags.clone(),
+            chunk_count: file.chunk_parts.len(),
+            chunk_guids,
+            download_size,
+        }
+    }
+}
+
+pub(crate) fn chunk_sizes(manifest: &Manifest) -> HashMap<String, u64> {
+    manifest.get_chunks().iter().map(|v| (guid_key(&v.guid), v.file_size as u64)).collect()
+}
@@ -0,0 +1,106 @@
+use crate::chunks::{self, ChunkSource};
+use crate::err::{WickError, WickResult};
+use crate::hash::sha_hex;
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::{Serialize, Deserialize};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+// Written at the root of an install once every file is in place
+pub const INSTALL_RECORD_FILE: &'static str = ".wickdl-install.json";
+
+#[cfg(unix)]
+const FILE_UNIX_EXECUTABLE: u8 = 0x04;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledFile {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallRecord {
+    pub app_name: String,
+    pub build_version: String,
+    pub files: Vec<InstalledFile>,
+}
+
+impl InstallRecord {
+    pub(crate) fn from_manifest(app_name: &str, build_version: &str, manifest: &Manifest) -> Self {
+        Self {
+            app_name: app_name.to_owned(),
+            build_version: build_version.to_owned(),
+            files: manifest.get_files().iter().map(|v| InstalledFile {
+                filename: v.filename.clone(),
+                hash: sha_hex(&v.file_hash),
+                size: file_size(v),
+            }).collect(),
+        }
+    }
+
+    pub async fn load<P>(dir: P) -> WickResult<Self> where P: AsRef<Path> {
+        let data = fs::read(dir.as_ref().join(INSTALL_RECORD_FILE)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub(crate) async fn store(&self, dir: &Path) -> WickResult<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(dir.join(INSTALL_RECORD_FILE), data).await?;
+        Ok(())
+    }
+}
+
+// Manifest filenames are relative, don't let one climb out of the install
+pub(crate) fn target_path(dir: &Path, filename: &str) -> WickResult<PathBuf> {
+    let relative = Path::new(filename);
+    if !relative.components().all(|v| matches!(v, Component::Normal(_))) {
+        return Err(WickError::new_str(format!("Refusing to install outside the target: {}", filename), 22));
+    }
+    Ok(dir.join(relative))
+}
+
+pub(crate) fn file_size(file: &FFileManifest) -> u64 {
+    file.chunk_parts.iter().map(|v| v.size as u64).sum()
+}
+
+// Files stay writable even when the manifest marks them read-only, so they can be patched in place later
+#[cfg(unix)]
+pub(crate) async fn apply_metadata(file: &FFileManifest, path: &Path) -> WickResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if file.file_meta_flags & FILE_UNIX_EXECUTABLE != 0 {
+        let mut permissions = fs::metadata(path).await?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        fs::set_permissions(path, permissions).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn apply_metadata(_file: &FFileManifest, _path: &Path) -> WickResult<()> {
+    Ok(())
+}
+
+pub(crate) async fn install_build(source: &ChunkSource, manifest: &Manifest, app_name: &str, build_version: &str, dir: &Path) -> WickResult<InstallRecord> {
+    let files = manifest.get_files();
+    let mut targets = Vec::with_capacity(files.len());
+    for file in files {
+        let path = target_path(dir, &file.filename)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        targets.push(path.to_string_lossy().into_owned());
+    }
+
+    let entries: Vec<(&FFileManifest, &str)> = files.iter().zip(targets.iter().map(|v| v.as_str())).collect();
+    chunks::download_files(source, manifest, &entries, |_, _| {}).await?;
+
+    for (file, target) in &entries {
+        apply_metadata(file, Path::new(target)).await?;
+    }
+
+    let record = InstallRecord::from_manifest(app_name, build_version, manifest);
+    record.store(dir).await?;
+
+    Ok(record)
+}
@@ -0,0 +1,371 @@
+use crate::err::{WickError, WickResult};
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha1::{Sha1, Digest};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Older builds shipped their chunk manifests as JSON. Rather than teach the parser a second
+// format, these get rewritten into the binary layout and handed to Manifest::from_buffer.
+
+const MANIFEST_MAGIC: u32 = 0x44BEC00C;
+const MANIFEST_HEADER_SIZE: u32 = 41;
+// JSON manifests don't record a window size, it was always 1MiB back then
+const LEGACY_WINDOW_SIZE: u32 = 1048576;
+
+const FILE_READ_ONLY: u8 = 0x01;
+const FILE_COMPRESSED: u8 = 0x02;
+const FILE_UNIX_EXECUTABLE: u8 = 0x04;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonManifest {
+    manifest_file_version: String,
+    #[serde(rename = "bIsFileData", default)]
+    is_file_data: bool,
+    #[serde(rename = "AppID", default)]
+    app_id: String,
+    #[serde(default)]
+    app_name_string: String,
+    #[serde(default)]
+    build_version_string: String,
+    #[serde(default)]
+    launch_exe_string: String,
+    #[serde(default)]
+    launch_command: String,
+    #[serde(default)]
+    prereq_ids: Vec<String>,
+    #[serde(default)]
+    prereq_name: String,
+    #[serde(default)]
+    prereq_path: String,
+    #[serde(default)]
+    prereq_args: String,
+    file_manifest_list: Vec<JsonFileManifest>,
+    chunk_hash_list: HashMap<String, String>,
+    #[serde(default)]
+    chunk_sha_list: HashMap<String, String>,
+    #[serde(default)]
+    data_group_list: HashMap<String, String>,
+    #[serde(default)]
+    chunk_filesize_list: HashMap<String, String>,
+    #[serde(default)]
+    custom_fields: HashMap<String, String>,
+}
+
+// Just enough to tell which version wrote the manifest
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonManifestVersion {
+    manifest_file_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonFileManifest {
+    filename: String,
+    file_hash: String,
+    #[serde(default)]
+    symlink_target: String,
+    file_chunk_parts: Vec<JsonChunkPart>,
+    #[serde(default)]
+    install_tags: Vec<String>,
+    #[serde(rename = "bIsUnixExecutable", default)]
+    is_unix_executable: bool,
+    #[serde(rename = "bIsReadOnly", default)]
+    is_read_only: bool,
+    #[serde(rename = "bIsCompressed", default)]
+    is_compressed: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JsonChunkPart {
+    guid: String,
+    offset: String,
+    size: String,
+}
+
+fn invalid(msg: &str) -> WickError {
+    WickError::new_str(format!("JSON Manifest Read Error: {}", msg), 15)
+}
+
+pub fn is_json_manifest(data: &[u8]) -> bool {
+    data.iter().find(|v| !v.is_ascii_whitespace()) == Some(&b'{')
+}
+
+// Blobs are three decimal digits per byte, least significant byte first
+fn blob_bytes(blob: &str) -> WickResult<Vec<u8>> {
+    if blob.len() % 3 != 0 || !blob.is_ascii() {
+        return Err(invalid(&format!("bad blob {}", blob)));
+    }
+    blob.as_bytes().chunks(3).map(|v| {
+        std::str::from_utf8(v).ok().and_then(|v| v.parse::<u8>().ok()).ok_or_else(|| invalid(&format!("bad blob {}", blob)))
+    }).collect()
+}
+
+fn blob_u64(blob: &str) -> WickResult<u64> {
+    let bytes = blob_bytes(blob)?;
+    if bytes.len() > 8 {
+        return Err(invalid(&format!("blob too long {}", blob)));
+    }
+    Ok(bytes.iter().rev().fold(0u64, |acc, v| (acc << 8) | *v as u64))
+}
+
+pub fn json_feature_level(data: &[u8]) -> WickResult<i32> {
+    let manifest: JsonManifestVersion = serde_json::from_slice(data)?;
+    Ok(blob_u64(&manifest.manifest_file_version)? as i32)
+}
+
+fn sha_bytes(data: Vec<u8>) -> [u8; 20] {
+    let mut sha = [0u8; 20];
+    let len = std::cmp::min(data.len(), 20);
+    sha[..len].copy_from_slice(&data[..len]);
+    sha
+}
+
+fn write_guid(out: &mut Vec<u8>, guid: &str) -> WickResult<()> {
+    if guid.len() != 32 || !guid.is_ascii() {
+        return Err(invalid(&format!("bad guid {}", guid)));
+    }
+    for i in 0..4 {
+        let part = u32::from_str_radix(&guid[(i * 8)..((i + 1) * 8)], 16).map_err(|_| invalid(&format!("bad guid {}", guid)))?;
+        out.write_u32::<LittleEndian>(part)?;
+    }
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, val: &str) -> WickResult<()> {
+    if val.is_empty() {
+        out.write_i32::<LittleEndian>(0)?;
+    } else if val.is_ascii() {
+        out.write_i32::<LittleEndian>(val.len() as i32 + 1)?;
+        out.extend_from_slice(val.as_bytes());
+        out.write_u8(0)?;
+    } else {
+        // Negative lengths mean UTF-16
+        let units: Vec<u16> = val.encode_utf16().collect();
+        out.write_i32::<LittleEndian>(-(units.len() as i32 + 1))?;
+        for unit in units {
+            out.write_u16::<LittleEndian>(unit)?;
+        }
+        out.write_u16::<LittleEndian>(0)?;
+    }
+    Ok(())
+}
+
+fn write_strings(out: &mut Vec<u8>, vals: &[String]) -> WickResult<()> {
+    out.write_i32::<LittleEndian>(vals.len() as i32)?;
+    for val in vals {
+        write_string(out, val)?;
+    }
+    Ok(())
+}
+
+// Every section starts with its own size and a version byte
+fn write_section<F>(out: &mut Vec<u8>, version: u8, body: F) -> WickResult<()> where F: FnOnce(&mut Vec<u8>) -> WickResult<()> {
+    let start = out.len();
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u8(version)?;
+    body(out)?;
+    let size = (out.len() - start) as u32;
+    out[start..(start + 4)].copy_from_slice(&size.to_le_bytes());
+    Ok(())
+}
+
+pub fn convert_json_manifest(data: &[u8]) -> WickResult<Vec<u8>> {
+    let manifest: JsonManifest = serde_json::from_slice(data)?;
+    let feature_level = blob_u64(&manifest.manifest_file_version)? as i32;
+
+    let mut chunk_guids: Vec<&String> = manifest.chunk_hash_list.keys().collect();
+    chunk_guids.sort();
+
+    let mut body = Vec::new();
+    write_section(&mut body, 0, |out| {
+        out.write_i32::<LittleEndian>(feature_level)?;
+        out.write_u8(manifest.is_file_data as u8)?;
+        out.write_u32::<LittleEndian>(blob_u64(&manifest.app_id).unwrap_or(0) as u32)?;
+        write_string(out, &manifest.app_name_string)?;
+        write_string(out, &manifest.build_version_string)?;
+        write_string(out, &manifest.launch_exe_string)?;
+        write_string(out, &manifest.launch_command)?;
+        write_strings(out, &manifest.prereq_ids)?;
+        write_string(out, &manifest.prereq_name)?;
+        write_string(out, &manifest.prereq_path)?;
+        write_string(out, &manifest.prereq_args)?;
+        Ok(())
+    })?;
+
+    write_section(&mut body, 0, |out| {
+        out.write_i32::<LittleEndian>(chunk_guids.len() as i32)?;
+        for guid in &chunk_guids {
+            write_guid(out, guid)?;
+        }
+        for guid in &chunk_guids {
+            out.write_u64::<LittleEndian>(blob_u64(&manifest.chunk_hash_list[*guid])?)?;
+        }
+        for guid in &chunk_guids {
+            let sha = match manifest.chunk_sha_list.get(*guid) {
+                Some(sha) => hex::decode(sha).map_err(|_| invalid(&format!("bad sha {}", sha)))?,
+                None => Vec::new(),
+            };
+            out.extend_from_slice(&sha_bytes(sha));
+        }
+        for guid in &chunk_guids {
+            let group = match manifest.data_group_list.get(*guid) {
+                Some(group) => blob_u64(group)? as u8,
+                None => 0,
+            };
+            out.write_u8(group)?;
+        }
+        for _ in &chunk_guids {
+            out.write_u32::<LittleEndian>(LEGACY_WINDOW_SIZE)?;
+        }
+        for guid in &chunk_guids {
+            let file_size = match manifest.chunk_filesize_list.get(*guid) {
+                Some(size) => blob_u64(size)? as i64,
+                None => 0,
+            };
+            out.write_i64::<LittleEndian>(file_size)?;
+        }
+        Ok(())
+    })?;
+
+    let files = &manifest.file_manifest_list;
+    write_section(&mut body, 0, |out| {
+        out.write_i32::<LittleEndian>(files.len() as i32)?;
+        for file in files {
+            write_string(out, &file.filename)?;
+        }
+        for file in files {
+            write_string(out, &file.symlink_target)?;
+        }
+        for file in files {
+            out.extend_from_slice(&sha_bytes(blob_bytes(&file.file_hash)?));
+        }
+        for file in files {
+            let mut flags = 0;
+            if file.is_read_only { flags |= FILE_READ_ONLY; }
+            if file.is_compressed { flags |= FILE_COMPRESSED; }
+            if file.is_unix_executable { flags |= FILE_UNIX_EXECUTABLE; }
+            out.write_u8(flags)?;
+        }
+        for file in files {
+            write_strings(out, &file.install_tags)?;
+        }
+        for file in files {
+            out.write_i32::<LittleEndian>(file.file_chunk_parts.len() as i32)?;
+            for part in &file.file_chunk_parts {
+                // Each part is its own little section with just a size, no version
+                out.write_u32::<LittleEndian>(28)?;
+                write_guid(out, &part.guid)?;
+                out.write_u32::<LittleEndian>(blob_u64(&part.offset)? as u32)?;
+                out.write_u32::<LittleEndian>(blob_u64(&part.size)? as u32)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut custom_fields: Vec<(&String, &String)> = manifest.custom_fields.iter().collect();
+    custom_fields.sort();
+    write_section(&mut body, 0, |out| {
+        out.write_i32::<LittleEndian>(custom_fields.len() as i32)?;
+        for (key, _) in &custom_fields {
+            write_string(out, key)?;
+        }
+        for (_, val) in &custom_fields {
+            write_string(out, val)?;
+        }
+        Ok(())
+    })?;
+
+    let mut result = Vec::with_capacity(body.len() + MANIFEST_HEADER_SIZE as usize);
+    result.write_u32::<LittleEndian>(MANIFEST_MAGIC)?;
+    result.write_u32::<LittleEndian>(MANIFEST_HEADER_SIZE)?;
+    result.write_u32::<LittleEndian>(body.len() as u32)?;
+    result.write_u32::<LittleEndian>(body.len() as u32)?;
+    result.extend_from_slice(&Sha1::digest(&body));
+    result.write_u8(0)?; // Stored uncompressed
+    result.write_i32::<LittleEndian>(feature_level)?;
+    result.extend_from_slice(&body);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{guid_key, sha_hex};
+    use john_wick_parse::manifest::Manifest;
+
+    const GUID: &'static str = "A1B2C3D40000000100000002DEADBEEF";
+
+    fn fixture() -> String {
+        format!(r#"{{
+            "ManifestFileVersion": "012000000000",
+            "bIsFileData": false,
+            "AppID": "000000000000",
+            "AppNameString": "FortniteLegacy",
+            "BuildVersionString": "++Fortnite+Release-3.5-CL-4008490-Windows",
+            "FileManifestList": [{{
+                "Filename": "FortniteGame/Content/Paks/pakchunk0-WindowsClient.pak",
+                "FileHash": "001002003004005006007008009010011012013014015016017018019020",
+                "FileChunkParts": [
+                    {{ "Guid": "{guid}", "Offset": "000000000000", "Size": "000000016000" }},
+                    {{ "Guid": "{guid}", "Offset": "000016000000", "Size": "001000000000" }}
+                ],
+                "bIsUnixExecutable": true
+            }}],
+            "ChunkHashList": {{ "{guid}": "239205171137103069035001" }},
+            "ChunkShaList": {{ "{guid}": "00112233445566778899aabbccddeeff00112233" }},
+            "DataGroupList": {{ "{guid}": "005" }},
+            "ChunkFilesizeList": {{ "{guid}": "000128000000" }}
+        }}"#, guid = GUID)
+    }
+
+    #[test]
+    fn blob_bytes_reads_three_digits_per_byte() {
+        assert_eq!(blob_bytes("001255000").unwrap(), vec![1, 255, 0]);
+        assert_eq!(blob_bytes("").unwrap(), Vec::<u8>::new());
+        assert!(blob_bytes("01").is_err());
+        assert!(blob_bytes("256").is_err());
+        assert!(blob_bytes("0a1").is_err());
+    }
+
+    #[test]
+    fn blob_u64_is_little_endian() {
+        assert_eq!(blob_u64("012000000000").unwrap(), 12);
+        assert_eq!(blob_u64("000000016000").unwrap(), 1048576);
+        assert_eq!(blob_u64("239205171137103069035001").unwrap(), 0x0123456789ABCDEF);
+        assert!(blob_u64("001002003004005006007008009").is_err());
+    }
+
+    #[test]
+    fn feature_level_comes_from_the_file_version() {
+        assert_eq!(json_feature_level(fixture().as_bytes()).unwrap(), 12);
+    }
+
+    #[test]
+    fn converted_manifest_parses() {
+        let data = fixture();
+        assert!(is_json_manifest(data.as_bytes()));
+        let manifest = Manifest::from_buffer(&convert_json_manifest(data.as_bytes()).unwrap()).unwrap();
+
+        let files = manifest.get_files();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.filename, "FortniteGame/Content/Paks/pakchunk0-WindowsClient.pak");
+        assert_eq!(sha_hex(&file.file_hash), "0102030405060708090a0b0c0d0e0f1011121314");
+        assert_eq!(file.chunk_parts.len(), 2);
+        assert_eq!(guid_key(&file.chunk_parts[0].guid), GUID);
+        assert_eq!((file.chunk_parts[0].offset, file.chunk_parts[0].size), (0, 1048576));
+        assert_eq!((file.chunk_parts[1].offset, file.chunk_parts[1].size), (4096, 1));
+
+        let chunks = manifest.get_chunks();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(guid_key(&chunks[0].guid), GUID);
+        assert_eq!(chunks[0].hash, 0x0123456789ABCDEF);
+        assert_eq!(sha_hex(&chunks[0].sha_hash), "00112233445566778899aabbccddeeff00112233");
+        assert_eq!(chunks[0].group_number, 5);
+        assert_eq!(chunks[0].window_size, LEGACY_WINDOW_SIZE);
+    }
+}
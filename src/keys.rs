@@ -0,0 +1,197 @@
+use crate::err::{WickError, WickResult};
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, NewBlockCipher, generic_array::GenericArray};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+
+// Where the key guid and container flags sit in a .utoc header
+const TOC_KEY_GUID_OFFSET: usize = 64;
+const TOC_CONTAINER_FLAGS_OFFSET: usize = 80;
+const CONTAINER_FLAG_ENCRYPTED: u8 = 0x02;
+const AES_BLOCK_SIZE: usize = 16;
+
+// Containers without a key guid use the main key, which goes under the zero guid
+pub const MAIN_KEY_GUID: &'static str = "00000000000000000000000000000000";
+
+fn invalid(msg: &str) -> WickError {
+    WickError::new_str(format!("Key Read Error: {}", msg), 20)
+}
+
+// GUIDs show up with and without dashes and braces, keys are kept under the bare uppercase form
+fn normalize_guid(guid: &str) -> WickResult<String> {
+    let guid: String = guid.chars().filter(|c| !matches!(c, '-' | '{' | '}')).collect();
+    if guid.len() != 32 || !guid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid(&format!("bad guid {}", guid)));
+    }
+    Ok(guid.to_uppercase())
+}
+
+fn parse_key(key: &str) -> WickResult<[u8; 32]> {
+    let data = match key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")) {
+        Some(key) => hex::decode(key).map_err(|_| invalid("key is not valid hex"))?,
+        None if key.len() == 64 => hex::decode(key).map_err(|_| invalid("key is not valid hex"))?,
+        None => base64::decode(key).map_err(|_| invalid("key is not valid hex or base64"))?,
+    };
+    if data.len() != 32 {
+        return Err(invalid(&format!("key is {} bytes, expected 32", data.len())));
+    }
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&data);
+    Ok(result)
+}
+
+// AES-256 keys for encrypted containers, by encryption key guid
+#[derive(Clone, Default)]
+pub struct KeyRing {
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A JSON object of guid to key, keys as 0x-prefixed hex, bare hex or base64
+    pub fn from_json(data: &str) -> WickResult<Self> {
+        let entries: HashMap<String, String> = serde_json::from_str(data)?;
+        let mut ring = Self::new();
+        for (guid, key) in entries {
+            ring.add_key(&guid, &key)?;
+        }
+        Ok(ring)
+    }
+
+    pub async fn load<P>(path: P) -> WickResult<Self> where P: AsRef<Path> {
+        let data = tokio::fs::read_to_string(path).await?;
+        Self::from_json(&data)
+    }
+
+    pub fn add_key(&mut self, guid: &str, key: &str) -> WickResult<()> {
+        self.keys.insert(normalize_guid(guid)?, parse_key(key)?);
+        Ok(())
+    }
+
+    pub fn add_main_key(&mut self, key: &str) -> WickResult<()> {
+        self.add_key(MAIN_KEY_GUID, key)
+    }
+
+    pub fn get_key(&self, guid: &str) -> Option<&[u8; 32]> {
+        let guid = normalize_guid(guid).ok()?;
+        self.keys.get(&guid)
+    }
+
+    pub fn get_guids(&self) -> Vec<&String> {
+        self.keys.keys().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// Reads the key guid of a .utoc straight from its header, None if the container isn't encrypted
+pub(crate) fn toc_key_guid(toc: &[u8]) -> WickResult<Option<String>> {
+    if toc.len() <= TOC_CONTAINER_FLAGS_OFFSET {
+        return Err(invalid("TOC header is too short"));
+    }
+    if toc[TOC_CONTAINER_FLAGS_OFFSET] & CONTAINER_FLAG_ENCRYPTED == 0 {
+        return Ok(None);
+    }
+
+    let mut cursor = Cursor::new(&toc[TOC_KEY_GUID_OFFSET..TOC_CONTAINER_FLAGS_OFFSET]);
+    let mut guid = String::with_capacity(32);
+    for _ in 0..4 {
+        guid += &format!("{:08X}", cursor.read_u32::<LittleEndian>()?);
+    }
+    Ok(Some(guid))
+}
+
+// Blocks are encrypted in place with AES-256 in ECB mode, so each 16 byte block stands alone
+pub(crate) fn decrypt(key: &[u8; 32], data: &mut [u8]) -> WickResult<()> {
+    if data.len() % AES_BLOCK_SIZE != 0 {
+        return Err(invalid("encrypted data is not a multiple of the block size"));
+    }
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncrypt;
+
+    const KEY_HEX: &'static str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key_bytes() -> [u8; 32] {
+        let mut key = [0u8; 32];
+        for (i, v) in key.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        key
+    }
+
+    #[test]
+    fn parses_key_formats() {
+        assert_eq!(parse_key(&format!("0x{}", KEY_HEX)).unwrap(), key_bytes());
+        assert_eq!(parse_key(&format!("0X{}", KEY_HEX.to_uppercase())).unwrap(), key_bytes());
+        assert_eq!(parse_key(KEY_HEX).unwrap(), key_bytes());
+        assert_eq!(parse_key(&base64::encode(key_bytes())).unwrap(), key_bytes());
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        assert_eq!(parse_key("0x0011").err().unwrap().get_code(), 20);
+        assert_eq!(parse_key(&format!("0x{}zz", &KEY_HEX[..62])).err().unwrap().get_code(), 20);
+        assert_eq!(parse_key(&base64::encode([1u8; 16])).err().unwrap().get_code(), 20);
+        assert_eq!(parse_key("not a key!").err().unwrap().get_code(), 20);
+    }
+
+    #[test]
+    fn normalizes_guids() {
+        let expected = "0123456789ABCDEF0123456789ABCDEF";
+        assert_eq!(normalize_guid("0123456789abcdef0123456789abcdef").unwrap(), expected);
+        assert_eq!(normalize_guid("{01234567-89AB-CDEF-0123-456789ABCDEF}").unwrap(), expected);
+        assert!(normalize_guid("0123456789ABCDEF").is_err());
+        assert!(normalize_guid("0123456789ABCDEF0123456789ABCDEG").is_err());
+    }
+
+    fn toc_header(encrypted: bool) -> Vec<u8> {
+        let mut toc = vec![0u8; 144];
+        for (i, part) in [0x01234567u32, 0x89ABCDEF, 0x00000001, 0xDEADBEEF].iter().enumerate() {
+            let at = TOC_KEY_GUID_OFFSET + i * 4;
+            toc[at..(at + 4)].copy_from_slice(&part.to_le_bytes());
+        }
+        if encrypted {
+            toc[TOC_CONTAINER_FLAGS_OFFSET] = CONTAINER_FLAG_ENCRYPTED | 0x01;
+        }
+        toc
+    }
+
+    #[test]
+    fn reads_toc_key_guid() {
+        assert_eq!(toc_key_guid(&toc_header(true)).unwrap().as_deref(), Some("0123456789ABCDEF00000001DEADBEEF"));
+        assert_eq!(toc_key_guid(&toc_header(false)).unwrap(), None);
+        assert_eq!(toc_key_guid(&[0u8; 40]).err().unwrap().get_code(), 20);
+    }
+
+    #[test]
+    fn decrypts_what_was_encrypted() {
+        let key = key_bytes();
+        let plain: Vec<u8> = (0..64u8).collect();
+        let mut data = plain.clone();
+        let cipher = Aes256::new(GenericArray::from_slice(&key));
+        for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        assert_ne!(data, plain);
+
+        decrypt(&key, &mut data).unwrap();
+        assert_eq!(data, plain);
+        assert_eq!(decrypt(&key, &mut [0u8; 15]).err().unwrap().get_code(), 20);
+    }
+}
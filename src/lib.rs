@@ -2,87 +2,231 @@ mod http;
 mod manifest;
 mod err;
 mod auth;
+mod oauth;
+mod token_cache;
 mod chunks;
 mod spool;
 mod reader;
+mod builder;
+mod archive;
+mod version;
+mod watcher;
+mod hash;
+mod diff;
+mod json_manifest;
+mod distribution;
+mod filter;
+mod info;
+mod pak;
+mod keys;
+mod vfs;
+mod install;
+mod patch;
+mod verify;
+mod repair;
+#[cfg(test)]
+mod test_server;
 
 use std::sync::{Arc, Mutex};
-pub use err::WickResult;
+pub use http::HttpService;
+pub use err::{WickResult, WickError, EpicError, EpicErrorKind};
+pub use auth::{AccessToken, TokenManager, AuthProvider, TokenFuture, ClientCredentials, StaticToken, NoAuth};
+pub use oauth::{UserAuth, DeviceAuthorization};
+pub use manifest::{AppManifest, ManifestQuery};
+pub use archive::ManifestArchive;
+pub use version::BuildVersion;
+pub use watcher::{BuildWatcher, NewBuild};
+pub use diff::ManifestDiff;
+pub use distribution::{DistributionPool, HostStatus};
+pub use filter::FileFilter;
+pub use info::FileInfo;
+pub use pak::PakService;
+pub use keys::KeyRing;
+pub use vfs::BuildFileSystem;
+pub use install::{InstallRecord, InstalledFile};
+pub use patch::PatchReport;
+pub use verify::{VerifyReport, CorruptFile, CorruptRange, RangeStatus};
+pub use repair::RepairReport;
+pub use builder::ServiceStateBuilder;
 use tokio::io::{AsyncReadExt};
 use john_wick_parse::dispatch::UtocManager;
 use john_wick_parse::manifest::{Manifest, FFileManifest};
 
 pub struct ServiceState {
-    http: Arc<crate::http::HttpService>,
-    app_manifest: manifest::AppManifest,
-    chunk_manifest: Manifest,
+    http: Arc<HttpService>,
+    tokens: TokenManager,
+    app_manifest: AppManifest,
+    distributions: Arc<DistributionPool>,
+    chunk_manifest: Arc<Manifest>,
+    chunk_dir: String,
     files: Vec<FFileManifest>,
+    request_count: usize,
+    keys: KeyRing,
 }
 
 pub struct UtocService {
     utoc: UtocManager,
     reader: Mutex<chunks::ChunkReader>,
+    key: Option<[u8; 32]>,
 }
 
 impl ServiceState {
     pub async fn new() -> WickResult<Self> {
-        let http_service = Arc::new(crate::http::HttpService::new());
-        let access_token = auth::get_token(&http_service).await?;
-        let app_manifest = manifest::get_manifest(&http_service, &access_token).await?;
-        let chunk_manifest = manifest::get_chunk_manifest(&http_service, &app_manifest).await?;
-
-        // Filter out just the pak files
-        let files = chunk_manifest.get_files().iter().filter(|v| {
-            let filename = &v.filename;
-            let ext = &filename[filename.len() - 5..];
-            (ext == ".utoc" || ext == ".ucas") && &filename[..8] == "Fortnite"
-        }).map(|v| v.clone()).collect();
+        ServiceStateBuilder::new().build().await
+    }
+
+    pub fn builder() -> ServiceStateBuilder {
+        ServiceStateBuilder::new()
+    }
+
+    pub(crate) async fn connect(http_service: Arc<HttpService>, tokens: TokenManager, query: &ManifestQuery, launcher_url: &str, archive: Option<&ManifestArchive>, filter: &FileFilter) -> WickResult<Self> {
+        let app_manifest = manifest::get_manifest(&http_service, &tokens, query, launcher_url).await?;
+        let chunk_data = manifest::get_chunk_manifest_data(&http_service, &app_manifest).await?;
+        if let Some(archive) = archive {
+            archive.store(&app_manifest, &chunk_data).await?;
+        }
+
+        Self::from_parts(http_service, tokens, app_manifest, &chunk_data, filter)
+    }
+
+    pub(crate) fn from_parts(http: Arc<HttpService>, tokens: TokenManager, app_manifest: AppManifest, chunk_data: &[u8], filter: &FileFilter) -> WickResult<Self> {
+        let chunk_manifest = manifest::parse_chunk_manifest(chunk_data)?;
+        let chunk_dir = chunks::chunk_dir(&app_manifest.get_cloud_dir()?, manifest::get_feature_level(chunk_data)?);
+        let files = chunk_manifest.get_files().iter().filter(|v| filter.matches(v)).cloned().collect();
 
         Ok(Self {
-            http: http_service,
+            http,
+            tokens,
+            distributions: Arc::new(DistributionPool::new(app_manifest.get_distributions()?)),
             app_manifest,
-            chunk_manifest,
+            chunk_manifest: Arc::new(chunk_manifest),
+            chunk_dir,
             files,
+            request_count: chunks::REQUEST_COUNT,
+            keys: KeyRing::new(),
         })
     }
 
     pub fn from_manifests(app_manifest: &str, chunk_manifest: &[u8]) -> WickResult<Self> {
-        let http_service = Arc::new(crate::http::HttpService::new());
+        Self::from_manifests_filtered(app_manifest, chunk_manifest, &FileFilter::default())
+    }
+
+    pub fn from_manifests_filtered(app_manifest: &str, chunk_manifest: &[u8], filter: &FileFilter) -> WickResult<Self> {
+        let http_service = Arc::new(HttpService::new());
         let app_manifest = manifest::create_app_manifest(app_manifest)?;
-        let chunk_manifest = Manifest::from_buffer(chunk_manifest)?;
 
-        // Filter out just the pak files
-        let files = chunk_manifest.get_files().iter().filter(|v| {
-            let filename = &v.filename;
-            let ext = &filename[filename.len() - 5..];
-            (ext == ".utoc" || ext == ".ucas") && &filename[..8] == "Fortnite"
-        }).map(|v| v.clone()).collect();
+        Self::from_parts(http_service, TokenManager::default(), app_manifest, chunk_manifest, filter)
+    }
 
-        Ok(Self {
-            http: http_service,
-            app_manifest,
-            chunk_manifest,
-            files,
-        })
+    // Never asks the launcher or account service for anything, token requests fail with code 17.
+    // Chunks still come from the CDN as normal.
+    pub(crate) async fn from_archive(http_service: Arc<HttpService>, archive: &ManifestArchive, build_version: Option<&str>, filter: &FileFilter) -> WickResult<Self> {
+        let build_version = match build_version {
+            Some(v) => v.to_owned(),
+            None => match archive.get_latest_build().await? {
+                Some(v) => v,
+                None => return err::make_err("No builds in the manifest archive"),
+            },
+        };
+        let (app_manifest, chunk_manifest) = archive.load(&build_version).await?;
+        let app_manifest = manifest::create_app_manifest(&app_manifest)?;
+
+        Self::from_parts(http_service, TokenManager::new(Box::new(auth::Offline)), app_manifest, &chunk_manifest, filter)
+    }
+
+    fn chunk_source(&self) -> chunks::ChunkSource {
+        chunks::ChunkSource {
+            http: self.http.clone(),
+            pool: self.distributions.clone(),
+            request_count: self.request_count,
+            chunk_dir: self.chunk_dir.clone(),
+        }
+    }
+
+    pub fn get_app_manifest(&self) -> &AppManifest {
+        &self.app_manifest
+    }
+
+    pub fn get_http(&self) -> Arc<HttpService> {
+        Arc::clone(&self.http)
+    }
+
+    pub fn get_distributions(&self) -> &DistributionPool {
+        &self.distributions
+    }
+
+    pub fn get_token_manager(&self) -> &TokenManager {
+        &self.tokens
+    }
+
+    pub fn get_key_ring(&self) -> &KeyRing {
+        &self.keys
     }
 
     pub fn get_paks(&self) -> Vec<String> {
         self.files.iter().map(|v| v.filename.to_owned()).collect()
     }
 
+    pub fn list_files(&self) -> Vec<FileInfo> {
+        let chunk_sizes = info::chunk_sizes(&self.chunk_manifest);
+        self.files.iter().map(|v| FileInfo::new(v, &chunk_sizes)).collect()
+    }
+
     pub async fn download_file(&self, file: String, target: String) -> WickResult<()> {
         let file = match self.files.iter().find(|v| v.filename == file) {
             Some(f) => f,
             None => return err::make_err("File does not exist"),
         };
 
-        chunks::download_file(self.http.clone(), &self.chunk_manifest, &self.app_manifest, &file, &target).await?;
+        chunks::download_file(&self.chunk_source(), &self.chunk_manifest, &file, &target).await?;
 
         Ok(())
     }
 
+    // Downloads several files at once under a single request limit. on_complete is called with the
+    // filename and target as each one finishes.
+    pub async fn download_files<F>(&self, files: &[(String, String)], on_complete: F) -> WickResult<()> where F: FnMut(&str, &str) {
+        let mut entries = Vec::with_capacity(files.len());
+        for (file, target) in files {
+            match self.files.iter().find(|v| &v.filename == file) {
+                Some(f) => entries.push((f, target.as_str())),
+                None => return err::make_err("File does not exist"),
+            }
+        }
+
+        chunks::download_files(&self.chunk_source(), &self.chunk_manifest, &entries, on_complete).await
+    }
+
+    // Installs every file in the build under target_dir, ignoring the file filter
+    pub async fn install_build<P>(&self, target_dir: P) -> WickResult<InstallRecord> where P: AsRef<std::path::Path> {
+        install::install_build(&self.chunk_source(), &self.chunk_manifest, self.app_manifest.get_app_name(), self.app_manifest.get_build_version(), target_dir.as_ref()).await
+    }
+
+    // Updates an install of the build old_manifest describes to this one, reusing what's already on disk
+    pub async fn patch_install<P>(&self, target_dir: P, old_manifest: &Manifest) -> WickResult<PatchReport> where P: AsRef<std::path::Path> {
+        patch::patch_install(&self.chunk_source(), old_manifest, &self.chunk_manifest, self.app_manifest.get_app_name(), self.app_manifest.get_build_version(), target_dir.as_ref()).await
+    }
+
+    // Checks an install of this build, every file in the manifest whatever the filter
+    pub async fn verify_install<P>(&self, target_dir: P) -> WickResult<VerifyReport> where P: AsRef<std::path::Path> {
+        let manifest = Arc::clone(&self.chunk_manifest);
+        let dir = target_dir.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || verify::verify_install(&manifest, &dir)).await?
+    }
+
+    // Re-downloads only the parts of target that fail verification. Any file in the build can be
+    // repaired, not just the ones the filter lets through, so this works on installs too.
+    pub async fn repair_file(&self, file: String, target: String) -> WickResult<RepairReport> {
+        let file = match self.chunk_manifest.get_files().iter().find(|v| v.filename == file) {
+            Some(f) => f,
+            None => return err::make_err("File does not exist"),
+        };
+
+        repair::repair_file(&self.chunk_source(), &self.chunk_manifest, file, &target).await
+    }
+
     pub async fn get_utoc(&self, file: &str) -> WickResult<UtocService> {
-        if &file[file.len() - 5..] != ".utoc" {
+        if !file.ends_with(".utoc") {
             return err::make_err("Invalid Index File");
         }
 
@@ -91,10 +235,18 @@ impl ServiceState {
             None => return err::make_err("File does not exist"),
         };
 
-        let mut reader = chunks::make_reader(self.http.clone(), &self.chunk_manifest, &self.app_manifest, &file_entry)?;
+        let mut reader = chunks::make_reader(&self.chunk_source(), &self.chunk_manifest, &file_entry)?;
         let mut buf = Vec::new();
         reader.read_to_end(&mut buf).await?;
-        let utoc = UtocManager::new(&buf, None)?;
+        let key = match keys::toc_key_guid(&buf)? {
+            Some(guid) => match self.keys.get_key(&guid) {
+                Some(key) => Some(*key),
+                None => return Err(WickError::new_str(format!("No key for {}, it is encrypted with key guid {}", file, guid), 21)),
+            },
+            None => None,
+        };
+        let key_hex = key.map(hex::encode);
+        let utoc = UtocManager::new(&buf, key_hex.as_deref())?;
 
         let mut ucas_file = file.to_owned();
         ucas_file.replace_range(file.len() - 5.., ".ucas");
@@ -104,13 +256,34 @@ impl ServiceState {
             None => return err::make_err("File does not exist"),
         };
 
-        let reader = chunks::make_reader(self.http.clone(), &self.chunk_manifest, &self.app_manifest, &file_entry)?;
+        let reader = chunks::make_reader(&self.chunk_source(), &self.chunk_manifest, &file_entry)?;
 
         Ok(UtocService {
             utoc,
             reader: Mutex::new(reader),
+            key,
         })
     }
+
+    // Every container in the build under one set of paths
+    pub fn file_system(&self) -> BuildFileSystem {
+        BuildFileSystem::new(self)
+    }
+
+    // Older builds ship .pak files, these need a filter that lets them through
+    pub async fn get_pak(&self, file: &str) -> WickResult<PakService> {
+        if !file.ends_with(".pak") {
+            return err::make_err("Invalid Pak File");
+        }
+
+        let file_entry = match self.files.iter().find(|v| v.filename == file) {
+            Some(f) => f,
+            None => return err::make_err("File does not exist"),
+        };
+
+        let reader = chunks::make_reader(&self.chunk_source(), &self.chunk_manifest, &file_entry)?;
+        PakService::new(reader).await
+    }
 }
 
 impl UtocService {
@@ -121,7 +294,7 @@ impl UtocService {
         };
 
         let mut ucas_reader = self.reader.lock().unwrap().reset();
-        let data = reader::get_chunk(&mut ucas_reader, self.utoc.get_reader_data(), &offset).await?;
+        let data = reader::get_chunk(&mut ucas_reader, self.utoc.get_reader_data(), &offset, self.key.as_ref()).await?;
 
         Ok(data)
     }
@@ -1,14 +1,53 @@
-use crate::http::HttpService;
-use crate::auth::AccessToken;
-use crate::err::{WickError, WickResult, make_err};
+use crate::http::{HttpService, url_encode};
+use crate::auth::TokenManager;
+use crate::version::BuildVersion;
+use crate::err::{WickError, WickResult, make_err, read_epic_json};
+use bytes::BytesMut;
+use crate::hash::sha1_hex;
+use crate::json_manifest::{is_json_manifest, convert_json_manifest, json_feature_level};
+use john_wick_parse::manifest::Manifest;
+use byteorder::{LittleEndian, ReadBytesExt};
+use flate2::read::ZlibDecoder;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use sha1::Digest;
+use sha2::Sha256;
 use std::collections::HashMap;
 use serde::{Deserialize};
-use hyper::{Request, Body};
-use john_wick_parse::manifest::Manifest;
-use std::io::Write;
-use std::fs::File;
+use hyper::{Body, StatusCode};
+
+const MANIFEST_MAGIC: u32 = 0x44BEC00C;
+
+pub const LAUNCHER_URL: &'static str = "https://launcher-public-service-prod06.ol.epicgames.com/launcher/api/public";
 
-const MANIFEST_URL: &'static str = "https://launcher-public-service-prod06.ol.epicgames.com/launcher/api/public/assets/Windows/4fe75bbc5a674f4f9b356b5c90567da5/Fortnite?label=Live";
+// Which build the launcher should hand back. Defaults to the live Windows build of Fortnite.
+#[derive(Debug, Clone)]
+pub struct ManifestQuery {
+    pub platform: String,
+    pub catalog_item_id: String,
+    pub app_name: String,
+    pub label: String,
+}
+
+impl ManifestQuery {
+    pub fn new(platform: &str, catalog_item_id: &str, app_name: &str, label: &str) -> Self {
+        Self {
+            platform: platform.to_owned(),
+            catalog_item_id: catalog_item_id.to_owned(),
+            app_name: app_name.to_owned(),
+            label: label.to_owned(),
+        }
+    }
+
+    fn get_url(&self, launcher_url: &str) -> String {
+        format!("{}/assets/{}/{}/{}?label={}", launcher_url, url_encode(&self.platform), url_encode(&self.catalog_item_id), url_encode(&self.app_name), url_encode(&self.label))
+    }
+}
+
+impl Default for ManifestQuery {
+    fn default() -> Self {
+        Self::new("Windows", "4fe75bbc5a674f4f9b356b5c90567da5", "Fortnite", "Live")
+    }
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -16,6 +55,8 @@ struct AppManifestItem {
     signature: String,
     distribution: String,
     path: String,
+    #[serde(default)]
+    hash: String,
     additional_distributions: Vec<String>,
 }
 
@@ -29,9 +70,55 @@ pub struct AppManifest {
     expires: String,
     asset_id: String,
     items: HashMap<String, AppManifestItem>,
+    // The JSON this was read from, kept around for archiving
+    #[serde(skip)]
+    raw: String,
 }
 
 impl AppManifest {
+    pub fn get_asset_id(&self) -> &str {
+        &self.asset_id
+    }
+
+    pub fn get_label_name(&self) -> &str {
+        &self.label_name
+    }
+
+    pub fn get_app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    pub fn get_catalog_item_id(&self) -> &str {
+        &self.catalog_item_id
+    }
+
+    pub fn get_expires(&self) -> &str {
+        &self.expires
+    }
+
+    pub fn get_build_version(&self) -> &str {
+        &self.build_version
+    }
+
+    pub fn parse_build_version(&self) -> WickResult<BuildVersion> {
+        BuildVersion::parse(&self.build_version)
+    }
+
+    pub fn get_raw(&self) -> &str {
+        &self.raw
+    }
+
+    // Chunks are kept under the directory the chunk manifest is in
+    pub fn get_cloud_dir(&self) -> WickResult<String> {
+        match self.items.get("MANIFEST") {
+            Some(item) => Ok(match item.path.rfind('/') {
+                Some(pos) => item.path[..(pos + 1)].to_owned(),
+                None => String::new(),
+            }),
+            None => make_err("Could not get manifest"),
+        }
+    }
+
     pub fn get_distributions(&self) -> WickResult<Vec<String>> {
         match self.items.get("MANIFEST") {
             Some(item) => {
@@ -46,39 +133,129 @@ impl AppManifest {
 }
 
 pub fn create_app_manifest(manifest: &str) -> WickResult<AppManifest> {
-    match serde_json::from_str(manifest) {
-        Ok(res) => Ok(res),
+    match serde_json::from_str::<AppManifest>(manifest) {
+        Ok(mut res) => {
+            res.raw = manifest.to_owned();
+            Ok(res)
+        },
         Err(_) => Err(WickError::new_str(format!("App Manifest Create Error: {}", &manifest[..std::cmp::min(200, manifest.len())]), 14)),
     }
 }
 
-pub async fn get_manifest(http: &HttpService, token: &AccessToken) -> WickResult<AppManifest> {
-    let req = Request::builder()
-        .method("GET")
-        .uri(MANIFEST_URL)
-        .header("Authorization", "bearer ".to_owned() + token.get_access_token())
-        .body(Body::empty())?;
+pub async fn get_manifest(http: &HttpService, tokens: &TokenManager, query: &ManifestQuery, launcher_url: &str) -> WickResult<AppManifest> {
+    match poll_manifest(http, tokens, query, launcher_url, None).await? {
+        Some((manifest, _etag)) => Ok(manifest),
+        None => make_err("Launcher returned no manifest"),
+    }
+}
+
+// Fetches the app manifest, or None if it hasn't changed since the given ETag.
+// The ETag of the returned manifest comes back alongside it, if the launcher sent one.
+pub async fn poll_manifest(http: &HttpService, tokens: &TokenManager, query: &ManifestQuery, launcher_url: &str, etag: Option<&str>) -> WickResult<Option<(AppManifest, Option<String>)>> {
+    let manifest_url = query.get_url(launcher_url);
+    let res = tokens.send(http, |builder| {
+        let builder = match etag {
+            Some(etag) => builder.header("If-None-Match", etag),
+            None => builder,
+        };
+        Ok(builder
+            .method("GET")
+            .uri(&manifest_url)
+            .body(Body::empty())?)
+    }).await?;
 
-    let manifest = http.post_url(req).await?;
+    if res.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
 
-    File::create("manifest.test").unwrap().write_all(&manifest[..])?;
-    let str_manifest = std::str::from_utf8(&manifest)?.to_owned();
+    let new_etag = match res.headers().get(hyper::header::ETAG) {
+        Some(val) => Some(val.to_str()?.to_owned()),
+        None => None,
+    };
+    let raw = std::str::from_utf8(res.body())?.to_owned();
+    let mut manifest: AppManifest = read_epic_json(res, "App Manifest Read Error", 14)?;
+    manifest.raw = raw;
+    Ok(Some((manifest, new_etag)))
+}
+
+// Hex SHA1 or SHA256 of the data, depending on what the launcher gave us
+fn check_manifest_hash(data: &[u8], expected: &str) -> WickResult<()> {
+    let actual = match expected.len() {
+        0 => return Ok(()),
+        40 => sha1_hex(data),
+        64 => hex::encode(Sha256::digest(data)),
+        _ => return Err(WickError::new_str(format!("Unrecognised chunk manifest hash: {}", expected), 15)),
+    };
 
-    match serde_json::from_str(&str_manifest) {
-        Ok(res) => Ok(res),
-        Err(_) => Err(WickError::new_str(format!("App Manifest Read Error: {}", &str_manifest[..std::cmp::min(200, manifest.len())]), 14))
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(WickError::new_str(format!("Chunk manifest hash mismatch, expected {} got {}", expected, actual), 16))
     }
 }
 
-pub async fn get_chunk_manifest(http: &HttpService, manifest: &AppManifest) -> WickResult<Manifest> {
+// Tries each distribution in turn until one gives back a manifest matching the expected hash
+pub async fn get_chunk_manifest_data(http: &HttpService, manifest: &AppManifest) -> WickResult<BytesMut> {
     let manifest_item = match manifest.items.get("MANIFEST") {
         Some(item) => item,
         None => make_err("Could not retrieve manifest")?,
     };
 
-    let manifest_url = manifest_item.distribution.clone() + &manifest_item.path + "?" + &manifest_item.signature;
-    let chunk_manifest = http.get_url(&manifest_url).await?;
+    let mut last_err = None;
+    for distribution in std::iter::once(&manifest_item.distribution).chain(manifest_item.additional_distributions.iter()) {
+        let manifest_url = distribution.clone() + &manifest_item.path + "?" + &manifest_item.signature;
+        let result = match http.get_url(&manifest_url).await {
+            Ok(data) => check_manifest_hash(&data, &manifest_item.hash).map(|_| data),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(data) => return Ok(data),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    match last_err {
+        Some(e) => Err(e),
+        None => make_err("No distributions to fetch the chunk manifest from"),
+    }
+}
+
+// Chunk manifests are binary, apart from older builds which used JSON
+pub fn parse_chunk_manifest(data: &[u8]) -> WickResult<Manifest> {
+    if is_json_manifest(data) {
+        let converted = convert_json_manifest(data)?;
+        return Ok(Manifest::from_buffer(&converted)?);
+    }
+    Ok(Manifest::from_buffer(data)?)
+}
+
+// The format version the chunk manifest was written with, which decides where its chunks are kept
+pub fn get_feature_level(data: &[u8]) -> WickResult<i32> {
+    if is_json_manifest(data) {
+        return json_feature_level(data);
+    }
 
-    let manifest_parse = Manifest::from_buffer(&chunk_manifest)?;
-    Ok(manifest_parse)
+    let invalid = || WickError::new_str("Chunk Manifest Read Error: bad header".to_owned(), 15);
+    let mut cursor = Cursor::new(data);
+    if cursor.read_u32::<LittleEndian>()? != MANIFEST_MAGIC {
+        return Err(invalid());
+    }
+    let header_size = cursor.read_u32::<LittleEndian>()?;
+    // Past the data sizes and SHA
+    cursor.seek(SeekFrom::Start(36))?;
+    let stored = cursor.read_u8()?;
+    if header_size >= 41 {
+        return Ok(cursor.read_i32::<LittleEndian>()?);
+    }
+
+    // Older headers don't have it, but the meta section always starts with its size, version and then this
+    let body = data.get((header_size as usize)..).ok_or_else(invalid)?;
+    let mut meta = [0u8; 9];
+    if stored & 1 != 0 {
+        ZlibDecoder::new(body).read_exact(&mut meta)?;
+    } else {
+        let mut body = body;
+        body.read_exact(&mut meta)?;
+    }
+    Ok(i32::from_le_bytes([meta[5], meta[6], meta[7], meta[8]]))
 }
@@ -0,0 +1,256 @@
+use crate::http::{HttpService, url_encode};
+use crate::err::{WickResult, WickError, read_epic_json};
+use crate::auth::{AccessToken, AuthProvider, ClientCredentials, TokenFuture, CLIENT_POST_DATA, post_token, parse_token, request_token};
+use hyper::{Request, Body};
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DEVICE_AUTHORIZATION_PATH: &'static str = "/oauth/deviceAuthorization";
+// Added to the polling interval every time the server says slow_down (RFC 8628)
+const SLOW_DOWN_STEP: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceAuthorization {
+    pub user_code: String,
+    pub device_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+enum UserGrant {
+    AuthorizationCode(String),
+    ExchangeCode(String),
+    DeviceCode,
+}
+
+// Tokens for a user account. The initial grant can only be used once, after which
+// every new token comes from the refresh token handed back with the last one.
+pub struct UserAuth {
+    client: ClientCredentials,
+    grant: Mutex<Option<UserGrant>>,
+    refresh_token: Mutex<Option<String>>,
+    device_prompt: Box<dyn Fn(&DeviceAuthorization) + Send + Sync>,
+}
+
+impl UserAuth {
+    fn new(client: ClientCredentials, grant: Option<UserGrant>, refresh_token: Option<String>) -> Self {
+        Self {
+            client,
+            grant: Mutex::new(grant),
+            refresh_token: Mutex::new(refresh_token),
+            device_prompt: Box::new(|_| {}),
+        }
+    }
+
+    pub fn authorization_code(client: ClientCredentials, code: &str) -> Self {
+        Self::new(client, Some(UserGrant::AuthorizationCode(code.to_owned())), None)
+    }
+
+    pub fn exchange_code(client: ClientCredentials, code: &str) -> Self {
+        Self::new(client, Some(UserGrant::ExchangeCode(code.to_owned())), None)
+    }
+
+    pub fn refresh_token(client: ClientCredentials, refresh_token: &str) -> Self {
+        Self::new(client, None, Some(refresh_token.to_owned()))
+    }
+
+    // The prompt is called with the code and URL the user needs to approve the login.
+    pub fn device_code<F>(client: ClientCredentials, prompt: F) -> Self where F: Fn(&DeviceAuthorization) + Send + Sync + 'static {
+        let mut auth = Self::new(client, Some(UserGrant::DeviceCode), None);
+        auth.device_prompt = Box::new(prompt);
+        auth
+    }
+
+    async fn request_grant(&self, http: &HttpService, account_url: &str, post_data: String) -> WickResult<AccessToken> {
+        request_token(http, account_url, self.client.get_authorization(), post_data).await
+    }
+
+    async fn device_code_grant(&self, http: &HttpService, account_url: &str) -> WickResult<AccessToken> {
+        let client_token = self.request_grant(http, account_url, CLIENT_POST_DATA.to_owned()).await?;
+        let req = Request::builder()
+            .method("POST")
+            .uri(account_url.to_owned() + DEVICE_AUTHORIZATION_PATH)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Authorization", "bearer ".to_owned() + client_token.get_access_token())
+            .body(Body::from("prompt=login"))?;
+
+        let device: DeviceAuthorization = read_epic_json(http.request(req).await?, "Device Authorization Error with Response", 13)?;
+        (self.device_prompt)(&device);
+
+        let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+        let mut interval = Duration::from_secs(std::cmp::max(device.interval, 1));
+        let post_data = format!("grant_type=device_code&device_code={}&token_type=eg1", url_encode(&device.device_code));
+        loop {
+            tokio::time::sleep(interval).await;
+            let res = post_token(http, account_url, self.client.get_authorization(), post_data.clone()).await?;
+            let err = match parse_token(res) {
+                Ok(token) => return Ok(token),
+                Err(err) => err,
+            };
+            let pending = match err.get_epic_error() {
+                Some(epic) if epic.get_error_code().ends_with("slow_down") => {
+                    interval += SLOW_DOWN_STEP;
+                    true
+                },
+                Some(epic) => epic.get_error_code().ends_with("authorization_pending"),
+                None => false,
+            };
+            if !pending || Instant::now() >= deadline {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl AuthProvider for UserAuth {
+    fn fetch_token<'a>(&'a self, http: &'a HttpService, account_url: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let refresh_token = self.refresh_token.lock().unwrap().clone();
+            let token = match refresh_token {
+                Some(refresh_token) => {
+                    self.request_grant(http, account_url, format!("grant_type=refresh_token&refresh_token={}&token_type=eg1", url_encode(&refresh_token))).await?
+                },
+                None => {
+                    let grant = self.grant.lock().unwrap().take();
+                    match grant {
+                        Some(UserGrant::AuthorizationCode(code)) => {
+                            self.request_grant(http, account_url, format!("grant_type=authorization_code&code={}&token_type=eg1", url_encode(&code))).await?
+                        },
+                        Some(UserGrant::ExchangeCode(code)) => {
+                            self.request_grant(http, account_url, format!("grant_type=exchange_code&exchange_code={}&token_type=eg1", url_encode(&code))).await?
+                        },
+                        Some(UserGrant::DeviceCode) => self.device_code_grant(http, account_url).await?,
+                        None => return Err(WickError::new_str("No user grant left to sign in with".to_owned(), 13)),
+                    }
+                },
+            };
+
+            *self.refresh_token.lock().unwrap() = token.get_refresh_token().map(|v| v.to_owned());
+            Ok(Some(token))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenManager;
+    use crate::test_server::{TestRequest, TestResponse, TestServer};
+    use std::collections::VecDeque;
+
+    const PENDING: &'static str = "errors.com.epicgames.account.oauth.authorization_pending";
+    const SLOW_DOWN: &'static str = "errors.com.epicgames.account.oauth.slow_down";
+    const EXPIRED: &'static str = "errors.com.epicgames.account.oauth.expired_device_code";
+
+    fn token(access_token: &str, refresh_token: &str) -> TestResponse {
+        TestResponse::new(200, &format!(r#"{{
+            "access_token": "{}", "expires_in": 7200, "expires_at": "2030-01-01T00:00:00.000Z", "token_type": "bearer",
+            "refresh_token": "{}", "refresh_expires_at": "2030-01-01T00:00:00.000Z"
+        }}"#, access_token, refresh_token))
+    }
+
+    fn epic_error(error_code: &str) -> TestResponse {
+        TestResponse::new(400, &format!(r#"{{ "errorCode": "{}", "errorMessage": "", "numericErrorCode": 0, "messageVars": [], "intent": "prod" }}"#, error_code))
+    }
+
+    fn is_device_poll(request: &TestRequest) -> bool {
+        request.path == "/oauth/token" && request.body.starts_with("grant_type=device_code")
+    }
+
+    // Device code polls get the responses in order, then are left pending
+    async fn account_service(expires_in: u64, polls: Vec<TestResponse>) -> TestServer {
+        let polls = Mutex::new(polls.into_iter().collect::<VecDeque<_>>());
+        TestServer::start(move |request| {
+            if request.path == "/oauth/deviceAuthorization" {
+                TestResponse::new(200, &format!(r#"{{
+                    "user_code": "ABCD1234", "device_code": "device", "verification_uri": "https://www.epicgames.com/activate",
+                    "verification_uri_complete": "https://www.epicgames.com/activate?userCode=ABCD1234", "expires_in": {}, "interval": 1
+                }}"#, expires_in))
+            } else if is_device_poll(request) {
+                polls.lock().unwrap().pop_front().unwrap_or_else(|| epic_error(PENDING))
+            } else {
+                token("client", "")
+            }
+        }).await
+    }
+
+    fn device_tokens(server: &TestServer) -> TokenManager {
+        TokenManager::new(Box::new(UserAuth::device_code(ClientCredentials::new("id", "secret"), |_| {}))).with_account_url(server.get_url())
+    }
+
+    fn device_polls(server: &TestServer) -> Vec<TestRequest> {
+        server.get_requests().into_iter().filter(is_device_poll).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_polling_while_pending() {
+        let server = account_service(600, vec![epic_error(PENDING), epic_error(PENDING), token("user", "r1")]).await;
+        let token = device_tokens(&server).get_token(&HttpService::new()).await.unwrap().unwrap();
+
+        assert_eq!(token.get_access_token(), "user");
+        let polls = device_polls(&server);
+        assert_eq!(polls.len(), 3);
+        assert!(polls[0].body.contains("device_code=device"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_down_stretches_the_interval() {
+        let server = account_service(600, vec![epic_error(PENDING), epic_error(SLOW_DOWN), epic_error(PENDING), token("user", "r1")]).await;
+        device_tokens(&server).get_token(&HttpService::new()).await.unwrap().unwrap();
+
+        let polls = device_polls(&server);
+        assert_eq!(polls.len(), 4);
+        let gaps: Vec<Duration> = polls.windows(2).map(|v| v[1].received - v[0].received).collect();
+        assert!(gaps[0] >= Duration::from_secs(1) && gaps[0] < Duration::from_secs(6));
+        assert!(gaps[1] >= Duration::from_secs(6));
+        assert!(gaps[2] >= Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_errors_stop_polling() {
+        let server = account_service(600, vec![epic_error(EXPIRED)]).await;
+        let err = device_tokens(&server).get_token(&HttpService::new()).await.err().unwrap();
+
+        assert_eq!(err.get_epic_error().unwrap().get_error_code(), EXPIRED);
+        assert_eq!(device_polls(&server).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_at_the_deadline() {
+        let server = account_service(3, Vec::new()).await;
+        let err = device_tokens(&server).get_token(&HttpService::new()).await.err().unwrap();
+
+        assert_eq!(err.get_epic_error().unwrap().get_error_code(), PENDING);
+        assert_eq!(device_polls(&server).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refresh_token_rotates() {
+        let server = TestServer::start(|request| {
+            if request.body.contains("refresh_token=r1") {
+                token("a1", "r2")
+            } else if request.body.contains("refresh_token=r2") {
+                token("a2", "r3")
+            } else {
+                epic_error("errors.com.epicgames.account.auth_token.invalid_refresh_token")
+            }
+        }).await;
+        let http = HttpService::new();
+        let tokens = TokenManager::new(Box::new(UserAuth::refresh_token(ClientCredentials::new("id", "secret"), "r1"))).with_account_url(server.get_url());
+
+        let first = tokens.get_token(&http).await.unwrap().unwrap();
+        assert_eq!(first.get_access_token(), "a1");
+        tokens.invalidate(&first).await.unwrap();
+        let second = tokens.get_token(&http).await.unwrap().unwrap();
+        assert_eq!(second.get_access_token(), "a2");
+
+        let bodies: Vec<String> = server.get_requests().into_iter().map(|v| v.body).collect();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies[0].starts_with("grant_type=refresh_token&refresh_token=r1&"));
+        assert!(bodies[1].starts_with("grant_type=refresh_token&refresh_token=r2&"));
+    }
+}
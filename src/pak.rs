@@ -0,0 +1,683 @@
+use crate::err::{WickError, WickResult, make_err};
+use crate::chunks::ChunkReader;
+use byteorder::{LittleEndian, ReadBytesExt};
+use flate2::bufread::{ZlibDecoder, GzDecoder};
+use john_wick_parse::decompress::oodle;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use tokio::io::AsyncReadExt;
+
+const PAK_MAGIC: u32 = 0x5A6F12E1;
+const COMPRESSION_NAME_LENGTH: usize = 32;
+// Versions where the layout of the pak changed
+const VERSION_NO_TIMESTAMPS: i32 = 2;
+const VERSION_COMPRESSION_ENCRYPTION: i32 = 3;
+const VERSION_RELATIVE_CHUNK_OFFSETS: i32 = 5;
+const VERSION_FNAME_COMPRESSION: i32 = 8;
+const VERSION_PATH_HASH_INDEX: i32 = 10;
+
+// Footer layouts we know about: total size, whether it starts with the encryption key guid,
+// whether it has the encrypted index flag, and how many compression method names follow
+const FOOTER_LAYOUTS: [(usize, bool, bool, usize); 6] = [
+    (221, true, true, 5),
+    (222, true, true, 5),
+    (189, true, true, 4),
+    (61, true, true, 0),
+    (45, false, true, 0),
+    (44, false, false, 0),
+];
+
+struct PakFooter {
+    version: i32,
+    encrypted_index: bool,
+    index_offset: i64,
+    index_size: i64,
+    compression_methods: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PakEntry {
+    offset: i64,
+    size: i64,
+    uncompressed_size: i64,
+    compression_method: u32,
+    // Start and end of each compressed block, relative to the entry on newer versions
+    blocks: Vec<(i64, i64)>,
+    encrypted: bool,
+    block_size: u32,
+}
+
+pub struct PakService {
+    version: i32,
+    mount_point: String,
+    files: Vec<String>,
+    entries: HashMap<String, PakEntry>,
+    compression_methods: Vec<String>,
+    reader: Mutex<ChunkReader>,
+}
+
+fn invalid(msg: &str) -> WickError {
+    WickError::new_str(format!("Pak Read Error: {}", msg), 19)
+}
+
+fn remaining(cursor: &Cursor<&[u8]>) -> u64 {
+    (cursor.get_ref().len() as u64).saturating_sub(cursor.position())
+}
+
+// Counts and lengths come straight from the pak, so check there's room for that many items
+// of at least item_size bytes before anything gets allocated for them
+fn read_count(cursor: &mut Cursor<&[u8]>, item_size: u64) -> WickResult<usize> {
+    let count = cursor.read_i32::<LittleEndian>()?;
+    if count < 0 || (count as u64).saturating_mul(item_size) > remaining(cursor) {
+        return Err(invalid(&format!("Count {} runs past the end of the data", count)));
+    }
+    Ok(count as usize)
+}
+
+fn read_string(cursor: &mut Cursor<&[u8]>) -> WickResult<String> {
+    let length = cursor.read_i32::<LittleEndian>()?;
+    if length == 0 {
+        return Ok(String::new());
+    }
+    let units = (length as i64).abs() as u64;
+    let unit_size = if length > 0 { 1 } else { 2 };
+    if units * unit_size > remaining(cursor) {
+        return Err(invalid(&format!("String length {} runs past the end of the data", length)));
+    }
+    if length > 0 {
+        let mut data = vec![0u8; units as usize];
+        cursor.read_exact(&mut data)?;
+        let data = match data.iter().position(|v| *v == 0) {
+            Some(pos) => &data[..pos],
+            None => &data[..],
+        };
+        return Ok(String::from_utf8_lossy(data).into_owned());
+    }
+
+    // Negative lengths are UTF-16
+    let mut data = vec![0u16; units as usize];
+    for unit in data.iter_mut() {
+        *unit = cursor.read_u16::<LittleEndian>()?;
+    }
+    let data = match data.iter().position(|v| *v == 0) {
+        Some(pos) => &data[..pos],
+        None => &data[..],
+    };
+    Ok(String::from_utf16_lossy(data))
+}
+
+// Offsets and sizes come straight from the pak, so check them before the reader goes looking for a chunk
+async fn read_range(reader: &mut ChunkReader, offset: i64, size: usize) -> WickResult<Vec<u8>> {
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+    let in_range = offset >= 0 && (offset as u64).checked_add(size as u64).map_or(false, |end| end <= reader.get_total_size());
+    if !in_range {
+        return Err(invalid(&format!("Range {}+{} is outside the pak", offset, size)));
+    }
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let mut buf = vec![0u8; size];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn read_footer(data: &[u8]) -> WickResult<PakFooter> {
+    for (size, has_guid, has_encrypted_flag, method_count) in FOOTER_LAYOUTS.iter() {
+        if *size > data.len() {
+            continue;
+        }
+        let mut cursor = Cursor::new(&data[(data.len() - size)..]);
+        if *has_guid {
+            cursor.seek(SeekFrom::Current(16))?;
+        }
+        let encrypted_index = if *has_encrypted_flag { cursor.read_u8()? != 0 } else { false };
+        if cursor.read_u32::<LittleEndian>()? != PAK_MAGIC {
+            continue;
+        }
+
+        let version = cursor.read_i32::<LittleEndian>()?;
+        let index_offset = cursor.read_i64::<LittleEndian>()?;
+        let index_size = cursor.read_i64::<LittleEndian>()?;
+        cursor.seek(SeekFrom::Current(20))?; // Index hash
+        if version == 9 {
+            cursor.seek(SeekFrom::Current(1))?; // Frozen index flag
+        }
+
+        let mut compression_methods = Vec::new();
+        for _ in 0..*method_count {
+            let mut name = [0u8; COMPRESSION_NAME_LENGTH];
+            cursor.read_exact(&mut name)?;
+            let end = name.iter().position(|v| *v == 0).unwrap_or(COMPRESSION_NAME_LENGTH);
+            if end > 0 {
+                compression_methods.push(String::from_utf8_lossy(&name[..end]).into_owned());
+            }
+        }
+        if version < VERSION_FNAME_COMPRESSION {
+            // Older paks used flags instead of names, these line up with what the flags meant
+            compression_methods = vec!["Zlib".to_owned(), "Gzip".to_owned(), "Oodle".to_owned()];
+        }
+
+        return Ok(PakFooter {
+            version,
+            encrypted_index,
+            index_offset,
+            index_size,
+            compression_methods,
+        });
+    }
+
+    Err(invalid("Could not find pak footer"))
+}
+
+// The entry as it is written in the legacy index, and again in front of the data
+fn read_entry(cursor: &mut Cursor<&[u8]>, version: i32) -> WickResult<PakEntry> {
+    let offset = cursor.read_i64::<LittleEndian>()?;
+    let size = cursor.read_i64::<LittleEndian>()?;
+    let uncompressed_size = cursor.read_i64::<LittleEndian>()?;
+    let compression_method = if version < VERSION_FNAME_COMPRESSION {
+        match cursor.read_i32::<LittleEndian>()? {
+            0 => 0,
+            0x01 => 1,
+            0x02 => 2,
+            _ => 3,
+        }
+    } else {
+        cursor.read_u32::<LittleEndian>()?
+    };
+    if version < VERSION_NO_TIMESTAMPS {
+        cursor.read_i64::<LittleEndian>()?;
+    }
+    let mut hash = [0u8; 20];
+    cursor.read_exact(&mut hash)?;
+
+    let mut blocks = Vec::new();
+    let mut encrypted = false;
+    let mut block_size = 0;
+    if version >= VERSION_COMPRESSION_ENCRYPTION {
+        if compression_method != 0 {
+            let block_count = read_count(cursor, 16)?;
+            for _ in 0..block_count {
+                let start = cursor.read_i64::<LittleEndian>()?;
+                let end = cursor.read_i64::<LittleEndian>()?;
+                blocks.push((start, end));
+            }
+        }
+        encrypted = cursor.read_u8()? & 0x01 != 0;
+        block_size = cursor.read_u32::<LittleEndian>()?;
+    }
+
+    Ok(PakEntry {
+        offset,
+        size,
+        uncompressed_size,
+        compression_method,
+        blocks,
+        encrypted,
+        block_size,
+    })
+}
+
+impl PakEntry {
+    // Size of the entry header that sits in front of the data
+    fn serialized_size(&self, version: i32) -> i64 {
+        let mut size = 8 + 8 + 8 + 4 + 20;
+        if version < VERSION_NO_TIMESTAMPS {
+            size += 8;
+        }
+        if version >= VERSION_COMPRESSION_ENCRYPTION {
+            if self.compression_method != 0 {
+                size += 4 + 16 * self.blocks.len() as i64;
+            }
+            size += 1 + 4;
+        }
+        size
+    }
+}
+
+// Entries in the path hash index format, packed into as few bytes as possible
+fn decode_entry(data: &[u8], offset: usize, version: i32) -> WickResult<PakEntry> {
+    if offset >= data.len() {
+        return Err(invalid("Encoded entry out of range"));
+    }
+    let mut cursor = Cursor::new(&data[offset..]);
+    let value = cursor.read_u32::<LittleEndian>()?;
+
+    let mut block_size = (value & 0x3f) << 11;
+    if value & 0x3f == 0x3f {
+        block_size = cursor.read_u32::<LittleEndian>()?;
+    }
+    let compression_method = (value >> 23) & 0x3f;
+    let encrypted = value & (1 << 22) != 0;
+    let block_count = ((value >> 6) & 0xffff) as usize;
+
+    let mut read_size = |safe: bool| -> WickResult<i64> {
+        Ok(if safe { cursor.read_u32::<LittleEndian>()? as i64 } else { cursor.read_i64::<LittleEndian>()? })
+    };
+    let entry_offset = read_size(value & (1 << 31) != 0)?;
+    let uncompressed_size = read_size(value & (1 << 30) != 0)?;
+    let size = if compression_method != 0 { read_size(value & (1 << 29) != 0)? } else { uncompressed_size };
+
+    let mut entry = PakEntry {
+        offset: entry_offset,
+        size,
+        uncompressed_size,
+        compression_method,
+        blocks: vec![(0, 0); block_count],
+        encrypted,
+        block_size,
+    };
+    if block_count == 0 {
+        return Ok(entry);
+    }
+    if (entry.uncompressed_size as u64) < entry.block_size as u64 {
+        entry.block_size = entry.uncompressed_size as u32;
+    }
+
+    // Blocks follow straight after the header in front of the data
+    let base_offset = entry.serialized_size(version);
+    if block_count == 1 && !encrypted {
+        let end = base_offset.checked_add(size).ok_or_else(|| invalid("Encoded entry size out of range"))?;
+        entry.blocks[0] = (base_offset, end);
+        return Ok(entry);
+    }
+
+    let mut block_offset = base_offset;
+    for block in entry.blocks.iter_mut() {
+        // Sizes are only 32 bits, so these can't overflow
+        let compressed_size = cursor.read_u32::<LittleEndian>()? as i64;
+        *block = (block_offset, block_offset + compressed_size);
+        block_offset += if encrypted { (compressed_size + 15) & !15 } else { compressed_size };
+    }
+
+    Ok(entry)
+}
+
+impl PakService {
+    pub(crate) async fn new(mut reader: ChunkReader) -> WickResult<Self> {
+        let total_size = reader.get_total_size();
+        let footer_size = std::cmp::min(total_size, FOOTER_LAYOUTS[1].0 as u64) as i64;
+        let footer_data = read_range(&mut reader, total_size as i64 - footer_size, footer_size as usize).await?;
+        let footer = read_footer(&footer_data)?;
+        if footer.encrypted_index {
+            return make_err("Encrypted pak indexes are not supported");
+        }
+
+        if footer.index_size < 0 {
+            return Err(invalid("Negative index size"));
+        }
+        let index = read_range(&mut reader, footer.index_offset, footer.index_size as usize).await?;
+        let mut cursor = Cursor::new(&index[..]);
+        let mount_point = read_string(&mut cursor)?;
+        let entry_count = read_count(&mut cursor, 4)?;
+
+        let mut files = Vec::new();
+        let mut entries = HashMap::new();
+        if footer.version < VERSION_PATH_HASH_INDEX {
+            for _ in 0..entry_count {
+                let filename = read_string(&mut cursor)?;
+                let entry = read_entry(&mut cursor, footer.version)?;
+                files.push(filename.clone());
+                entries.insert(filename, entry);
+            }
+        } else {
+            cursor.read_u64::<LittleEndian>()?; // Path hash seed
+            if cursor.read_u32::<LittleEndian>()? != 0 {
+                // We go through the full directory index instead of the path hash index
+                cursor.seek(SeekFrom::Current(8 + 8 + 20))?;
+            }
+            let (directory_offset, directory_size) = match cursor.read_u32::<LittleEndian>()? {
+                0 => return Err(invalid("Pak has no full directory index")),
+                _ => (cursor.read_i64::<LittleEndian>()?, cursor.read_i64::<LittleEndian>()?),
+            };
+            cursor.seek(SeekFrom::Current(20))?;
+
+            let encoded_size = read_count(&mut cursor, 1)?;
+            let mut encoded = vec![0u8; encoded_size];
+            cursor.read_exact(&mut encoded)?;
+            let unencoded_count = read_count(&mut cursor, 1)?;
+            let mut unencoded = Vec::new();
+            for _ in 0..unencoded_count {
+                unencoded.push(read_entry(&mut cursor, footer.version)?);
+            }
+
+            if directory_size < 0 {
+                return Err(invalid("Negative directory index size"));
+            }
+            let directory_data = read_range(&mut reader, directory_offset, directory_size as usize).await?;
+            let mut cursor = Cursor::new(&directory_data[..]);
+            let directory_count = read_count(&mut cursor, 8)?;
+            for _ in 0..directory_count {
+                let directory = read_string(&mut cursor)?;
+                let file_count = read_count(&mut cursor, 8)?;
+                for _ in 0..file_count {
+                    let name = read_string(&mut cursor)?;
+                    let location = cursor.read_i32::<LittleEndian>()?;
+                    // Negative locations index the entries that couldn't be encoded
+                    let entry = if location < 0 {
+                        match unencoded.get((-(location as i64) - 1) as usize) {
+                            Some(entry) => entry.clone(),
+                            None => return Err(invalid("Unencoded entry out of range")),
+                        }
+                    } else {
+                        decode_entry(&encoded, location as usize, footer.version)?
+                    };
+                    let filename = directory.trim_start_matches('/').to_owned() + &name;
+                    files.push(filename.clone());
+                    entries.insert(filename, entry);
+                }
+            }
+        }
+
+        Ok(Self {
+            version: footer.version,
+            mount_point,
+            files,
+            entries,
+            compression_methods: footer.compression_methods,
+            reader: Mutex::new(reader),
+        })
+    }
+
+    fn decompress(&self, method: u32, data: &[u8], size: usize) -> WickResult<Vec<u8>> {
+        let name = match self.compression_methods.get(method as usize - 1) {
+            Some(name) => name.to_lowercase(),
+            None => return make_err("Unknown pak compression method"),
+        };
+        let mut result = Vec::with_capacity(size);
+        match name.as_str() {
+            "zlib" => { ZlibDecoder::new(data).read_to_end(&mut result)?; },
+            "gzip" => { GzDecoder::new(data).read_to_end(&mut result)?; },
+            "oodle" => { result = oodle::decompress_stream(size as u64, data).map_err(|_| invalid("Could not decompress oodle block"))?; },
+            _ => return Err(invalid(&format!("Unsupported compression method {}", name))),
+        }
+        Ok(result)
+    }
+
+    pub async fn get_file(&self, file: &str) -> WickResult<Vec<u8>> {
+        let entry = match self.entries.get(file) {
+            Some(e) => e,
+            None => return make_err("File not found"),
+        };
+        if entry.encrypted {
+            return make_err("Encrypted pak entries are not supported");
+        }
+
+        if entry.size < 0 || entry.uncompressed_size < 0 {
+            return Err(invalid("Negative entry size"));
+        }
+
+        let mut reader = self.reader.lock().unwrap().reset();
+        if entry.compression_method == 0 {
+            let offset = entry.offset.checked_add(entry.serialized_size(self.version)).ok_or_else(|| invalid("Entry offset out of range"))?;
+            return read_range(&mut reader, offset, entry.size as usize).await;
+        }
+
+        // Nothing is reserved up front, the size is only as trustworthy as the pak
+        if entry.uncompressed_size as u64 > entry.blocks.len() as u64 * entry.block_size as u64 {
+            return Err(invalid("Entry is larger than its blocks"));
+        }
+        // Block offsets are only relative to the entry from version 5 on
+        let base = if self.version >= VERSION_RELATIVE_CHUNK_OFFSETS { entry.offset } else { 0 };
+        let mut data = Vec::new();
+        for (start, end) in &entry.blocks {
+            let remaining = match (entry.uncompressed_size as usize).checked_sub(data.len()) {
+                Some(remaining) => remaining,
+                None => return Err(invalid("Blocks decompressed past the end of the entry")),
+            };
+            let block_size = std::cmp::min(entry.block_size as usize, remaining);
+            let offset = base.checked_add(*start).ok_or_else(|| invalid("Block offset out of range"))?;
+            let length = match end.checked_sub(*start) {
+                Some(length) if length >= 0 => length as usize,
+                _ => return Err(invalid("Block ends before it starts")),
+            };
+            let block = read_range(&mut reader, offset, length).await?;
+            data.extend_from_slice(&self.decompress(entry.compression_method, &block, block_size)?);
+        }
+        data.truncate(entry.uncompressed_size as usize);
+
+        Ok(data)
+    }
+
+    // Paths relative to the mount point
+    pub fn get_file_list(&self) -> &Vec<String> {
+        &self.files
+    }
+
+    pub fn get_mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    pub fn get_file_size(&self, file: &str) -> Option<u64> {
+        self.entries.get(file).map(|v| v.uncompressed_size as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    // Footer bytes for a layout from FOOTER_LAYOUTS, padded in front like the tail read from a real pak
+    fn footer(layout: usize, version: i32, methods: &[&str]) -> Vec<u8> {
+        let (size, has_guid, has_encrypted_flag, method_count) = FOOTER_LAYOUTS[layout];
+        let mut out = Vec::new();
+        if has_guid {
+            out.extend_from_slice(&[0xAA; 16]);
+        }
+        if has_encrypted_flag {
+            out.write_u8(0).unwrap();
+        }
+        out.write_u32::<LittleEndian>(PAK_MAGIC).unwrap();
+        out.write_i32::<LittleEndian>(version).unwrap();
+        out.write_i64::<LittleEndian>(1000).unwrap();
+        out.write_i64::<LittleEndian>(200).unwrap();
+        out.extend_from_slice(&[0xBB; 20]);
+        if version == 9 {
+            out.write_u8(0).unwrap();
+        }
+        for i in 0..method_count {
+            let mut name = [0u8; COMPRESSION_NAME_LENGTH];
+            if let Some(method) = methods.get(i) {
+                name[..method.len()].copy_from_slice(method.as_bytes());
+            }
+            out.extend_from_slice(&name);
+        }
+        assert_eq!(out.len(), size);
+
+        let mut data = vec![0u8; 222 - size];
+        data.extend_from_slice(&out);
+        data
+    }
+
+    fn entry_header(version: i32, compression_method: u32, blocks: &[(i64, i64)], flags: u8, block_size: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.write_i64::<LittleEndian>(4096).unwrap();
+        out.write_i64::<LittleEndian>(300).unwrap();
+        out.write_i64::<LittleEndian>(500).unwrap();
+        if version < VERSION_FNAME_COMPRESSION {
+            out.write_i32::<LittleEndian>(compression_method as i32).unwrap();
+        } else {
+            out.write_u32::<LittleEndian>(compression_method).unwrap();
+        }
+        if version < VERSION_NO_TIMESTAMPS {
+            out.write_i64::<LittleEndian>(0).unwrap();
+        }
+        out.extend_from_slice(&[0xCC; 20]);
+        if version >= VERSION_COMPRESSION_ENCRYPTION {
+            if compression_method != 0 {
+                out.write_i32::<LittleEndian>(blocks.len() as i32).unwrap();
+                for (start, end) in blocks {
+                    out.write_i64::<LittleEndian>(*start).unwrap();
+                    out.write_i64::<LittleEndian>(*end).unwrap();
+                }
+            }
+            out.write_u8(flags).unwrap();
+            out.write_u32::<LittleEndian>(block_size).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn reads_every_footer_layout() {
+        let layouts = [(0, 11), (1, 9), (2, 8), (3, 7), (4, 4), (5, 3)];
+        for (layout, version) in layouts.iter() {
+            let footer = read_footer(&footer(*layout, *version, &["Oodle", "Zlib"])).unwrap();
+            assert_eq!(footer.version, *version);
+            assert_eq!((footer.index_offset, footer.index_size), (1000, 200));
+            assert!(!footer.encrypted_index);
+            if *version >= VERSION_FNAME_COMPRESSION {
+                assert_eq!(footer.compression_methods, vec!["Oodle", "Zlib"]);
+            } else {
+                assert_eq!(footer.compression_methods, vec!["Zlib", "Gzip", "Oodle"]);
+            }
+        }
+    }
+
+    #[test]
+    fn footer_needs_the_magic() {
+        let mut data = footer(0, 11, &[]);
+        let magic_at = data.len() - 221 + 17;
+        data[magic_at] ^= 0xFF;
+        assert_eq!(read_footer(&data).err().unwrap().get_code(), 19);
+        assert_eq!(read_footer(&[0u8; 10]).err().unwrap().get_code(), 19);
+    }
+
+    #[test]
+    fn reads_entries_before_compression_blocks() {
+        for version in [1, 2].iter() {
+            let data = entry_header(*version, 1, &[], 0, 0);
+            let mut cursor = Cursor::new(&data[..]);
+            let entry = read_entry(&mut cursor, *version).unwrap();
+            assert_eq!((entry.offset, entry.size, entry.uncompressed_size), (4096, 300, 500));
+            assert_eq!(entry.compression_method, 1);
+            assert!(entry.blocks.is_empty());
+            assert!(!entry.encrypted);
+            assert_eq!(cursor.position() as i64, entry.serialized_size(*version));
+            assert_eq!(cursor.position(), data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn reads_entries_with_compression_blocks() {
+        let data = entry_header(8, 2, &[(100, 250), (250, 400)], 0x01, 65536);
+        let mut cursor = Cursor::new(&data[..]);
+        let entry = read_entry(&mut cursor, 8).unwrap();
+        assert_eq!(entry.compression_method, 2);
+        assert_eq!(entry.blocks, vec![(100, 250), (250, 400)]);
+        assert!(entry.encrypted);
+        assert_eq!(entry.block_size, 65536);
+        assert_eq!(cursor.position() as i64, entry.serialized_size(8));
+
+        // Uncompressed entries have no block list at all
+        let data = entry_header(3, 0, &[], 0, 0);
+        let entry = read_entry(&mut Cursor::new(&data[..]), 3).unwrap();
+        assert!(entry.blocks.is_empty());
+        assert_eq!(entry.serialized_size(3), data.len() as i64);
+    }
+
+    #[test]
+    fn rejects_entries_with_impossible_block_counts() {
+        let mut data = entry_header(8, 1, &[], 0, 0);
+        let count_at = 8 + 8 + 8 + 4 + 20;
+        data[count_at..(count_at + 4)].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert_eq!(read_entry(&mut Cursor::new(&data[..]), 8).err().unwrap().get_code(), 19);
+        data[count_at..(count_at + 4)].copy_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(read_entry(&mut Cursor::new(&data[..]), 8).err().unwrap().get_code(), 19);
+    }
+
+    fn encoded_flags(compression_method: u32, block_count: u32, block_size_bits: u32, encrypted: bool) -> u32 {
+        (compression_method << 23) | (block_count << 6) | block_size_bits | if encrypted { 1 << 22 } else { 0 }
+    }
+
+    #[test]
+    fn decodes_32_bit_sizes() {
+        let mut data = vec![0xFF, 0xFF];
+        data.write_u32::<LittleEndian>(encoded_flags(1, 1, 0x20, false) | (1 << 31) | (1 << 30) | (1 << 29)).unwrap();
+        data.write_u32::<LittleEndian>(7000).unwrap();
+        data.write_u32::<LittleEndian>(100000).unwrap();
+        data.write_u32::<LittleEndian>(40000).unwrap();
+
+        let entry = decode_entry(&data, 2, 11).unwrap();
+        assert_eq!((entry.offset, entry.uncompressed_size, entry.size), (7000, 100000, 40000));
+        assert_eq!(entry.compression_method, 1);
+        assert_eq!(entry.block_size, 0x20 << 11);
+        assert!(!entry.encrypted);
+        // A single unencrypted block covers the whole entry, right after its header
+        let base = entry.serialized_size(11);
+        assert_eq!(base, 48 + 4 + 16 + 5);
+        assert_eq!(entry.blocks, vec![(base, base + 40000)]);
+    }
+
+    #[test]
+    fn decodes_64_bit_sizes() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(encoded_flags(0, 0, 0, false)).unwrap();
+        data.write_i64::<LittleEndian>(0x1_0000_0000).unwrap();
+        data.write_i64::<LittleEndian>(0x2_0000_0000).unwrap();
+
+        let entry = decode_entry(&data, 0, 11).unwrap();
+        assert_eq!(entry.offset, 0x1_0000_0000);
+        // Uncompressed entries don't store the size twice
+        assert_eq!((entry.uncompressed_size, entry.size), (0x2_0000_0000, 0x2_0000_0000));
+        assert!(entry.blocks.is_empty());
+    }
+
+    #[test]
+    fn aligns_encrypted_blocks() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(encoded_flags(1, 2, 0x3f, true) | (1 << 31) | (1 << 30) | (1 << 29)).unwrap();
+        data.write_u32::<LittleEndian>(131072).unwrap(); // Block size that didn't fit the flags
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(200000).unwrap();
+        data.write_u32::<LittleEndian>(30).unwrap();
+        data.write_u32::<LittleEndian>(10).unwrap();
+        data.write_u32::<LittleEndian>(20).unwrap();
+
+        let entry = decode_entry(&data, 0, 11).unwrap();
+        assert!(entry.encrypted);
+        assert_eq!(entry.block_size, 131072);
+        let base = 48 + 4 + 32 + 5;
+        assert_eq!(entry.blocks, vec![(base, base + 10), (base + 16, base + 36)]);
+    }
+
+    #[test]
+    fn block_size_is_clamped_to_small_entries() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(encoded_flags(1, 1, 0x20, false) | (1 << 31) | (1 << 30) | (1 << 29)).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(500).unwrap();
+        data.write_u32::<LittleEndian>(300).unwrap();
+
+        assert_eq!(decode_entry(&data, 0, 11).unwrap().block_size, 500);
+        assert_eq!(decode_entry(&data, data.len(), 11).err().unwrap().get_code(), 19);
+    }
+
+    #[test]
+    fn rejects_bad_string_lengths() {
+        for length in [i32::MIN, -1000, 1000, i32::MAX].iter() {
+            let mut data = Vec::new();
+            data.write_i32::<LittleEndian>(*length).unwrap();
+            data.extend_from_slice(b"short\0");
+            assert_eq!(read_string(&mut Cursor::new(&data[..])).err().unwrap().get_code(), 19);
+        }
+
+        let mut data = Vec::new();
+        data.write_i32::<LittleEndian>(6).unwrap();
+        data.extend_from_slice(b"short\0");
+        data.write_i32::<LittleEndian>(-3).unwrap();
+        data.extend_from_slice(&[b'h', 0, b'i', 0, 0, 0]);
+        let mut cursor = Cursor::new(&data[..]);
+        assert_eq!(read_string(&mut cursor).unwrap(), "short");
+        assert_eq!(read_string(&mut cursor).unwrap(), "hi");
+    }
+
+    #[test]
+    fn rejects_bad_counts() {
+        let data = [0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(read_count(&mut Cursor::new(&data[..]), 1).err().unwrap().get_code(), 19);
+        let data = [2, 0, 0, 0, 1, 2, 3];
+        assert_eq!(read_count(&mut Cursor::new(&data[..]), 2).err().unwrap().get_code(), 19);
+        assert_eq!(read_count(&mut Cursor::new(&data[..]), 1).unwrap(), 2);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::chunks::{self, ChunkDownload, ChunkSource};
+use crate::diff::ManifestDiff;
+use crate::err::{WickError, WickResult};
+use crate::hash::{guid_key, sha_hex};
+use crate::install::{self, InstallRecord};
+use crate::verify::{self, ChunkCheck};
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// New versions of files are built next to the old ones, then renamed over them
+const PATCH_SUFFIX: &'static str = ".wickdl-patch";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchReport {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+    // Bytes copied out of the old local files
+    pub reused_bytes: u64,
+    // Bytes of file data that had to come from the CDN
+    pub downloaded_bytes: u64,
+}
+
+// Where a piece of an old file lives on disk
+struct LocalPart {
+    path: PathBuf,
+    chunk_offset: u32,
+    size: u32,
+    position: u64,
+}
+
+// Every chunk range we already have locally, by chunk guid
+fn index_local_parts(dir: &Path, old: &Manifest) -> WickResult<HashMap<String, Vec<LocalPart>>> {
+    let mut parts: HashMap<String, Vec<LocalPart>> = HashMap::new();
+    for file in old.get_files() {
+        let path = install::target_path(dir, &file.filename)?;
+        let local_size = match std::fs::metadata(&path) {
+            Ok(meta) => meta.len(),
+            Err(_) => continue,
+        };
+
+        let mut position = 0;
+        for part in &file.chunk_parts {
+            if position + part.size as u64 <= local_size {
+                parts.entry(guid_key(&part.guid)).or_default().push(LocalPart {
+                    path: path.clone(),
+                    chunk_offset: part.offset,
+                    size: part.size,
+                    position,
+                });
+            }
+            position += part.size as u64;
+        }
+    }
+    Ok(parts)
+}
+
+fn find_local<'a>(local: &'a HashMap<String, Vec<LocalPart>>, guid: &str, download: &ChunkDownload) -> Option<(&'a Path, u64)> {
+    let start = download.offset;
+    let end = download.offset as u64 + download.length as u64;
+    local.get(guid)?.iter()
+        .find(|v| v.chunk_offset <= start && end <= v.chunk_offset as u64 + v.size as u64)
+        .map(|v| (v.path.as_path(), v.position + (start - v.chunk_offset) as u64))
+}
+
+fn patch_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(PATCH_SUFFIX);
+    PathBuf::from(name)
+}
+
+// Builds the new version of a file in its patch path, copying whatever the old files already have
+// and handing back the parts that still need downloading. Copied whole chunks are checked against
+// their hash, anything that fails is downloaded instead.
+async fn build_from_local(chunk_dir: &str, local: &HashMap<String, Vec<LocalPart>>, checks: &HashMap<String, ChunkCheck>, manifest: &Manifest, file: &FFileManifest, target: &Path, report: &mut PatchReport) -> WickResult<Vec<ChunkDownload>> {
+    let downloads = chunks::plan_downloads(chunk_dir, manifest, file)?;
+    let mut out = File::create(target).await?;
+    out.set_len(install::file_size(file)).await?;
+
+    let mut sources: HashMap<PathBuf, File> = HashMap::new();
+    let mut remote = Vec::new();
+    let mut buf = Vec::new();
+    for (part, download) in file.chunk_parts.iter().zip(downloads) {
+        let guid = guid_key(&part.guid);
+        let (path, position) = match find_local(local, &guid, &download) {
+            Some(found) => found,
+            None => {
+                remote.push(download);
+                continue;
+            },
+        };
+
+        if !sources.contains_key(path) {
+            sources.insert(path.to_path_buf(), File::open(path).await?);
+        }
+        let source = sources.get_mut(path).unwrap();
+        buf.resize(download.length as usize, 0);
+        source.seek(SeekFrom::Start(position)).await?;
+        source.read_exact(&mut buf).await?;
+        if checks.get(&guid).and_then(|v| v.check_part(download.offset, &buf)) == Some(false) {
+            remote.push(download);
+            continue;
+        }
+
+        out.seek(SeekFrom::Start(download.position)).await?;
+        out.write_all(&buf).await?;
+        report.reused_bytes += download.length as u64;
+    }
+    out.flush().await?;
+
+    Ok(remote)
+}
+
+async fn matches_manifest(file: &FFileManifest, path: &str) -> WickResult<bool> {
+    let expected = sha_hex(&file.file_hash);
+    let path = path.to_owned();
+    let actual = tokio::task::spawn_blocking(move || verify::hash_file(&mut std::fs::File::open(path)?)).await??;
+    Ok(actual == expected)
+}
+
+// Brings an install of the old build up to the new one. Nothing is renamed into place until every
+// changed file has been built, so the old files stay readable as sources the whole way through.
+pub(crate) async fn patch_install(source: &ChunkSource, old: &Manifest, new: &Manifest, app_name: &str, build_version: &str, dir: &Path) -> WickResult<PatchReport> {
+    let diff = ManifestDiff::between(old, new);
+    let mut report = PatchReport {
+        added: diff.get_added().clone(),
+        modified: diff.get_modified().clone(),
+        removed: diff.get_removed().clone(),
+        reused_bytes: 0,
+        downloaded_bytes: 0,
+    };
+
+    let local = index_local_parts(dir, old)?;
+    let checks = verify::chunk_checks(new);
+    let new_files: HashMap<&str, &FFileManifest> = new.get_files().iter().map(|v| (v.filename.as_str(), v)).collect();
+    let mut changed = Vec::new();
+    for name in report.added.iter().chain(report.modified.iter()) {
+        let file = new_files[name.as_str()];
+        let target = install::target_path(dir, name)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        changed.push((file, target));
+    }
+
+    let temp_paths: Vec<String> = changed.iter().map(|v| patch_path(&v.1).to_string_lossy().into_owned()).collect();
+    let built = async {
+        let mut remote = Vec::new();
+        let mut downloaded = Vec::with_capacity(changed.len());
+        for (i, (file, _)) in changed.iter().enumerate() {
+            let downloads = build_from_local(&source.chunk_dir, &local, &checks, new, file, Path::new(&temp_paths[i]), &mut report).await?;
+            downloaded.push(downloads.iter().map(|v| v.length as u64).sum::<u64>());
+            remote.extend(downloads.into_iter().map(|v| (i, v)));
+        }
+        report.downloaded_bytes += downloaded.iter().sum::<u64>();
+
+        let targets: Vec<&str> = temp_paths.iter().map(|v| v.as_str()).collect();
+        chunks::download_parts(source, remote, &targets, |_| {}).await?;
+
+        // Partial chunks copied from the old files can't be checked on their own, so check the
+        // whole file and fetch all of it when something reused was bad
+        let mut retried = Vec::new();
+        let mut retry = Vec::new();
+        for (i, (file, _)) in changed.iter().enumerate() {
+            if !matches_manifest(file, &temp_paths[i]).await? {
+                let size = install::file_size(file);
+                report.reused_bytes -= size - downloaded[i];
+                report.downloaded_bytes += size - downloaded[i];
+                retry.extend(chunks::plan_downloads(&source.chunk_dir, new, file)?.into_iter().map(|v| (i, v)));
+                retried.push(i);
+            }
+        }
+        if retried.is_empty() {
+            return Ok(());
+        }
+        chunks::download_parts(source, retry, &targets, |_| {}).await?;
+        for i in retried {
+            if !matches_manifest(changed[i].0, &temp_paths[i]).await? {
+                return Err(WickError::new_str(format!("{} doesn't match the manifest hash after patching", changed[i].0.filename), 22));
+            }
+        }
+        Ok(()) as WickResult<()>
+    }.await;
+    if built.is_err() {
+        // Leave the install as it was
+        for path in &temp_paths {
+            let _ = fs::remove_file(path).await;
+        }
+    }
+    built?;
+
+    for (i, (file, target)) in changed.iter().enumerate() {
+        fs::rename(&temp_paths[i], target).await?;
+        install::apply_metadata(file, target).await?;
+    }
+    for name in &report.removed {
+        let path = install::target_path(dir, name)?;
+        if fs::metadata(&path).await.is_ok() {
+            fs::remove_file(&path).await?;
+        }
+    }
+
+    InstallRecord::from_manifest(app_name, build_version, new).store(dir).await?;
+
+    Ok(report)
+}
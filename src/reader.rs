@@ -1,26 +1,35 @@
 use std::sync::Arc;
 use std::io::{Seek, SeekFrom};
 use tokio::io::AsyncReadExt;
-use crate::err::WickResult;
+use crate::err::{WickError, WickResult};
 use crate::chunks::ChunkReader;
+use crate::keys;
 use john_wick_parse::decompress::oodle;
 use john_wick_parse::dispatch::{ReaderData, FIoStoreTocCompressedBlockEntry, FIoOffsetAndLength, align_value};
 
-async fn get_block(reader: &mut ChunkReader, block: &FIoStoreTocCompressedBlockEntry) -> WickResult<Vec<u8>> {
+async fn get_block(reader: &mut ChunkReader, block: &FIoStoreTocCompressedBlockEntry, key: Option<&[u8; 32]>) -> WickResult<Vec<u8>> {
     reader.seek(SeekFrom::Start(block.offset))?;
 
     let block_size = align_value(block.compressed_size, 16) as usize;
     let mut buf = vec![0u8; block_size];
     reader.read_exact(&mut buf).await?;
 
+    if let Some(key) = key {
+        keys::decrypt(key, &mut buf)?;
+    }
+
     if block.compression_method == 0 {
         return Ok(buf);
     }
 
-    Ok(oodle::decompress_stream(block.size as u64, &buf).unwrap())
+    // With the wrong key the block decrypts to garbage, and this is where it shows
+    oodle::decompress_stream(block.size as u64, &buf).map_err(|_| match key {
+        Some(_) => WickError::new_str("Could not decompress block, wrong key for container".to_owned(), 21),
+        None => WickError::new_str("Could not decompress block".to_owned(), 12),
+    })
 }
 
-pub async fn get_chunk(reader: &mut ChunkReader, data: Arc<ReaderData>, chunk: &FIoOffsetAndLength) -> WickResult<Vec<u8>> {
+pub async fn get_chunk(reader: &mut ChunkReader, data: Arc<ReaderData>, chunk: &FIoOffsetAndLength, key: Option<&[u8; 32]>) -> WickResult<Vec<u8>> {
     let length = chunk.length as usize;
     let mut buf = vec![0u8; length];
     let mut written: usize = 0;
@@ -30,7 +39,7 @@ pub async fn get_chunk(reader: &mut ChunkReader, data: Arc<ReaderData>, chunk: &
     while written < length {
         let block_idx = pos / block_size;
         let block = data.get_block(block_idx).unwrap();
-        let block_data = get_block(reader, block).await?;
+        let block_data = get_block(reader, block, key).await?;
         let offset = pos % block_size;
         let to_write = std::cmp::min(block.size as usize - offset, length - written);
 
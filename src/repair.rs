@@ -0,0 +1,89 @@
+use crate::chunks::{self, ChunkDownload, ChunkSource};
+use crate::err::WickResult;
+use crate::install;
+use crate::verify::{self, ChunkCheck, CorruptRange, CorruptFile};
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs::OpenOptions;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairReport {
+    pub filename: String,
+    // Ranges that were downloaded again, empty if the file was already fine
+    pub repaired: Vec<CorruptRange>,
+    // Whether the file matches the manifest hash afterwards
+    pub verified: bool,
+}
+
+async fn check_file(checks: Arc<HashMap<String, ChunkCheck>>, file: FFileManifest, path: PathBuf) -> WickResult<Option<CorruptFile>> {
+    tokio::task::spawn_blocking(move || {
+        verify::verify_file(&file, &path, &checks)
+    }).await?
+}
+
+// Only the parts at corrupt positions are fetched again
+fn plan_repair(chunk_dir: &str, manifest: &Manifest, file: &FFileManifest, corrupt: &CorruptFile) -> WickResult<Vec<ChunkDownload>> {
+    let positions: HashSet<u64> = corrupt.ranges.iter().map(|v| v.position).collect();
+    Ok(chunks::plan_downloads(chunk_dir, manifest, file)?.into_iter().filter(|v| positions.contains(&v.position)).collect())
+}
+
+// Rewrites the parts of target that fail verification, leaving the rest of the file where it is.
+// Parts that can't be checked on their own are fetched too when the file as a whole is bad.
+pub(crate) async fn repair_file(source: &ChunkSource, manifest: &Manifest, file: &FFileManifest, target: &str) -> WickResult<RepairReport> {
+    let path = PathBuf::from(target);
+    let handle = OpenOptions::new().write(true).create(true).truncate(false).open(&path).await?;
+
+    let checks = Arc::new(verify::chunk_checks(manifest));
+    let corrupt = match check_file(checks.clone(), file.clone(), path.clone()).await? {
+        Some(corrupt) => corrupt,
+        None => return Ok(RepairReport {
+            filename: file.filename.clone(),
+            repaired: Vec::new(),
+            verified: true,
+        }),
+    };
+
+    // Growing or shrinking keeps everything before the new end in place
+    if corrupt.actual_size != corrupt.expected_size {
+        handle.set_len(install::file_size(file)).await?;
+    }
+    drop(handle);
+
+    let parts = plan_repair(&source.chunk_dir, manifest, file, &corrupt)?.into_iter().map(|v| (0, v)).collect();
+    chunks::download_parts(source, parts, &[target], |_| {}).await?;
+
+    let verified = check_file(checks, file.clone(), path).await?.is_none();
+
+    Ok(RepairReport {
+        filename: file.filename.clone(),
+        repaired: corrupt.ranges,
+        verified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::tests::{fixture, temp_file, WINDOW};
+
+    #[tokio::test]
+    async fn only_corrupt_ranges_are_scheduled() {
+        let (manifest, mut contents) = fixture(&[(1, 0, WINDOW), (2, 0, WINDOW), (3, 0, WINDOW)]);
+        contents[2 * WINDOW as usize + 7] ^= 0xFF;
+        let path = temp_file("repair", &contents);
+        let file = &manifest.get_files()[0];
+
+        let checks = Arc::new(verify::chunk_checks(&manifest));
+        let corrupt = check_file(checks, file.clone(), path.clone()).await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let planned = plan_repair("Builds/Test/CloudDir/ChunksV3/", &manifest, file, &corrupt).unwrap();
+
+        assert_eq!(planned.len(), 1);
+        assert_eq!((planned[0].position, planned[0].length, planned[0].offset, planned[0].index), (2 * WINDOW as u64, WINDOW, 0, 2));
+        assert!(planned[0].path.starts_with("Builds/Test/CloudDir/ChunksV3/"));
+        assert!(planned[0].path.ends_with(&format!("_{}.chunk", crate::verify::tests::chunk_guid(3))));
+    }
+}
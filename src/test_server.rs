@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+// A local stand-in for the launcher, account service or a CDN host. Every request is recorded,
+// and the handler decides what goes back. Connections are closed after each response.
+
+#[derive(Debug, Clone)]
+pub(crate) struct TestRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) body: String,
+    // On the tokio clock, so paused tests see virtual time
+    pub(crate) received: Instant,
+    headers: Vec<(String, String)>,
+}
+
+impl TestRequest {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|v| v.0.eq_ignore_ascii_case(name)).map(|v| v.1.as_str())
+    }
+}
+
+pub(crate) struct TestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl TestResponse {
+    pub(crate) fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_owned(),
+        }
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+pub(crate) struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<TestRequest>>>,
+}
+
+impl TestServer {
+    pub(crate) async fn start<F>(handler: F) -> Self where F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let recorded = Arc::clone(&recorded);
+                let handler = Arc::clone(&handler);
+                tokio::spawn(async move {
+                    let _ = serve(socket, handler.as_ref(), &recorded).await;
+                });
+            }
+        });
+
+        Self {
+            url,
+            requests,
+        }
+    }
+
+    // No trailing slash
+    pub(crate) fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) fn get_requests(&self) -> Vec<TestRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve<F>(mut socket: TcpStream, handler: &F, recorded: &Mutex<Vec<TestRequest>>) -> std::io::Result<()> where F: Fn(&TestRequest) -> TestResponse {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        data.extend_from_slice(&buf[..read]);
+        if let Some(pos) = data.windows(4).position(|v| v == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_owned();
+    let path = request_line.next().unwrap_or("").to_owned();
+    let headers: Vec<(String, String)> = lines.filter_map(|v| {
+        let pos = v.find(':')?;
+        Some((v[..pos].trim().to_owned(), v[(pos + 1)..].trim().to_owned()))
+    }).collect();
+    let length: usize = headers.iter().find(|v| v.0.eq_ignore_ascii_case("content-length")).and_then(|v| v.1.parse().ok()).unwrap_or(0);
+    while data.len() < header_end + length {
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..read]);
+    }
+    let body_end = std::cmp::min(data.len(), header_end + length);
+
+    let request = TestRequest {
+        method,
+        path,
+        body: String::from_utf8_lossy(&data[header_end..body_end]).into_owned(),
+        received: Instant::now(),
+        headers,
+    };
+    let response = handler(&request);
+    // Recorded before answering, so the client never sees a response for a request that isn't listed yet
+    recorded.lock().unwrap().push(request);
+
+    let mut out = format!("HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+    for (name, value) in &response.headers {
+        out += &format!("{}: {}\r\n", name, value);
+    }
+    out += "\r\n";
+    out += &response.body;
+    socket.write_all(out.as_bytes()).await?;
+    socket.shutdown().await
+}
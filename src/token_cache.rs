@@ -0,0 +1,84 @@
+use crate::auth::AccessToken;
+use crate::err::WickResult;
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// A token stored on disk so separate processes can share it.
+// Every read-modify-write happens under an exclusive lock on a sibling .lock file.
+pub struct TokenCache {
+    path: PathBuf,
+}
+
+// Released when dropped
+pub struct TokenCacheLock {
+    _file: File,
+}
+
+// The cache holds a live credential, so only the owner gets to read it
+#[cfg_attr(not(unix), allow(unused_mut))]
+fn private_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+impl TokenCache {
+    pub fn new<P>(path: P) -> Self where P: AsRef<Path> {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    pub async fn lock(&self) -> WickResult<TokenCacheLock> {
+        let lock_path = self.lock_path();
+        // Waiting on the lock blocks, so keep it off the runtime threads
+        let file = tokio::task::spawn_blocking(move || -> std::io::Result<File> {
+            let file = private_options().create(true).write(true).truncate(false).open(lock_path)?;
+            file.lock_exclusive()?;
+            Ok(file)
+        }).await??;
+
+        Ok(TokenCacheLock {
+            _file: file,
+        })
+    }
+
+    // Anything unreadable is treated as an empty cache.
+    pub fn read(&self, _lock: &TokenCacheLock) -> Option<AccessToken> {
+        let data = fs::read(&self.path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn write(&self, _lock: &TokenCacheLock, token: &AccessToken) -> WickResult<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        // The mode only applies to new files, so don't reuse one left behind by an older version
+        let _ = fs::remove_file(&temp_path);
+        let mut file = private_options().create_new(true).write(true).open(&temp_path)?;
+        file.write_all(&serde_json::to_vec(token)?)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, _lock: &TokenCacheLock) -> WickResult<()> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::err::WickResult;
+use crate::hash::{guid_key, sha_hex};
+use crate::install::{self, INSTALL_RECORD_FILE};
+use john_wick_parse::manifest::{Manifest, FFileManifest};
+use serde::Serialize;
+use sha1::{Sha1, Digest};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangeStatus {
+    // The bytes don't match the chunk they came from
+    Corrupt,
+    // Only part of a chunk is used here, so its hash can't be checked. One of these is the culprit
+    // when a file fails but none of its ranges are corrupt.
+    Unverifiable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CorruptRange {
+    pub position: u64,
+    pub length: u32,
+    pub chunk_guid: String,
+    pub status: RangeStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CorruptFile {
+    pub filename: String,
+    pub expected_hash: String,
+    pub actual_hash: String,
+    pub expected_size: u64,
+    pub actual_size: u64,
+    pub ranges: Vec<CorruptRange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub corrupt: Vec<CorruptFile>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+pub(crate) struct ChunkCheck {
+    sha: String,
+    size: u32,
+}
+
+impl ChunkCheck {
+    // None when the part doesn't cover the whole chunk, or there's no hash to check it against
+    pub(crate) fn check_part(&self, offset: u32, data: &[u8]) -> Option<bool> {
+        if offset != 0 || data.len() != self.size as usize || self.sha.is_empty() {
+            return None;
+        }
+        Some(hex::encode(Sha1::digest(data)) == self.sha)
+    }
+}
+
+pub(crate) fn hash_file(file: &mut File) -> WickResult<String> {
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0u8; 1048576];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// A part can only be checked when it is a whole chunk, the chunk hashes cover the full window
+fn check_ranges(file: &FFileManifest, local: &mut File, actual_size: u64, chunks: &HashMap<String, ChunkCheck>) -> WickResult<Vec<CorruptRange>> {
+    let mut ranges = Vec::new();
+    let mut position = 0;
+    let mut buf = Vec::new();
+    for part in &file.chunk_parts {
+        let guid = guid_key(&part.guid);
+        let status = if position + part.size as u64 > actual_size {
+            Some(RangeStatus::Corrupt)
+        } else {
+            match chunks.get(&guid) {
+                Some(chunk) if part.offset == 0 && part.size == chunk.size && !chunk.sha.is_empty() => {
+                    buf.resize(part.size as usize, 0);
+                    local.seek(SeekFrom::Start(position))?;
+                    local.read_exact(&mut buf)?;
+                    match chunk.check_part(part.offset, &buf) {
+                        Some(true) => None,
+                        _ => Some(RangeStatus::Corrupt),
+                    }
+                },
+                _ => Some(RangeStatus::Unverifiable),
+            }
+        };
+
+        if let Some(status) = status {
+            ranges.push(CorruptRange {
+                position,
+                length: part.size,
+                chunk_guid: guid,
+                status,
+            });
+        }
+        position += part.size as u64;
+    }
+    Ok(ranges)
+}
+
+pub(crate) fn verify_file(file: &FFileManifest, path: &Path, chunks: &HashMap<String, ChunkCheck>) -> WickResult<Option<CorruptFile>> {
+    let mut local = File::open(path)?;
+    let actual_size = local.metadata()?.len();
+    let expected_size = install::file_size(file);
+    let expected_hash = sha_hex(&file.file_hash);
+    let actual_hash = hash_file(&mut local)?;
+    if actual_hash == expected_hash && actual_size == expected_size {
+        return Ok(None);
+    }
+
+    Ok(Some(CorruptFile {
+        filename: file.filename.clone(),
+        ranges: check_ranges(file, &mut local, actual_size, chunks)?,
+        expected_hash,
+        actual_hash,
+        expected_size,
+        actual_size,
+    }))
+}
+
+fn find_extra(dir: &Path, relative: &str, known: &HashSet<&str>, extra: &mut Vec<String>) -> WickResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = relative.to_owned() + &entry.file_name().to_string_lossy();
+        if entry.file_type()?.is_dir() {
+            find_extra(&entry.path(), &(name + "/"), known, extra)?;
+        } else if !known.contains(name.as_str()) && name != INSTALL_RECORD_FILE {
+            extra.push(name);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn chunk_checks(manifest: &Manifest) -> HashMap<String, ChunkCheck> {
+    manifest.get_chunks().iter().map(|v| {
+        // Manifests converted from JSON can be missing chunk hashes, which leaves them zeroed
+        let sha = sha_hex(&v.sha_hash);
+        let sha = if sha.chars().all(|c| c == '0') { String::new() } else { sha };
+        (guid_key(&v.guid), ChunkCheck {
+            sha,
+            size: v.window_size,
+        })
+    }).collect()
+}
+
+// Hashing is all blocking work, so this is meant to be run off the async threads
+pub(crate) fn verify_install(manifest: &Manifest, dir: &Path) -> WickResult<VerifyReport> {
+    let chunks = chunk_checks(manifest);
+
+    let mut report = VerifyReport {
+        checked: 0,
+        missing: Vec::new(),
+        extra: Vec::new(),
+        corrupt: Vec::new(),
+    };
+    for file in manifest.get_files() {
+        let path = install::target_path(dir, &file.filename)?;
+        if !path.is_file() {
+            report.missing.push(file.filename.clone());
+            continue;
+        }
+        report.checked += 1;
+        if let Some(corrupt) = verify_file(file, &path, &chunks)? {
+            report.corrupt.push(corrupt);
+        }
+    }
+
+    // Manifests always use forward slashes
+    let known: HashSet<&str> = manifest.get_files().iter().map(|v| v.filename.as_str()).collect();
+    find_extra(dir, "", &known, &mut report.extra)?;
+    report.extra.sort();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hash::sha1_hex;
+    use crate::manifest::parse_chunk_manifest;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    // What JSON manifests assume every chunk's window is
+    pub(crate) const WINDOW: u32 = 1048576;
+
+    fn blob(bytes: &[u8]) -> String {
+        bytes.iter().map(|v| format!("{:03}", v)).collect()
+    }
+
+    pub(crate) fn chunk_guid(n: u8) -> String {
+        format!("{:032X}", n)
+    }
+
+    // The whole window of chunk n
+    pub(crate) fn chunk_data(n: u8) -> Vec<u8> {
+        (0..WINDOW).map(|v| (v as u8).wrapping_mul(31).wrapping_add(n)).collect()
+    }
+
+    // A build with a single file, test.bin, made of (chunk, offset, size) parts. Hands back the
+    // manifest and what the file should contain.
+    pub(crate) fn fixture(parts: &[(u8, u32, u32)]) -> (Manifest, Vec<u8>) {
+        let mut contents = Vec::new();
+        let mut chunks = BTreeSet::new();
+        let mut json_parts = Vec::new();
+        for (n, offset, size) in parts {
+            contents.extend_from_slice(&chunk_data(*n)[(*offset as usize)..((offset + size) as usize)]);
+            chunks.insert(*n);
+            json_parts.push(format!(r#"{{ "Guid": "{}", "Offset": "{}", "Size": "{}" }}"#, chunk_guid(*n), blob(&offset.to_le_bytes()), blob(&size.to_le_bytes())));
+        }
+        let hashes: Vec<String> = chunks.iter().map(|n| format!(r#""{}": "{}""#, chunk_guid(*n), blob(&(*n as u64).to_le_bytes()))).collect();
+        let shas: Vec<String> = chunks.iter().map(|n| format!(r#""{}": "{}""#, chunk_guid(*n), sha1_hex(&chunk_data(*n)))).collect();
+        let json = format!(
+            r#"{{ "ManifestFileVersion": "{}", "FileManifestList": [{{ "Filename": "test.bin", "FileHash": "{}", "FileChunkParts": [{}] }}], "ChunkHashList": {{ {} }}, "ChunkShaList": {{ {} }} }}"#,
+            blob(&12u32.to_le_bytes()), blob(&Sha1::digest(&contents)), json_parts.join(","), hashes.join(","), shas.join(","),
+        );
+        (parse_chunk_manifest(json.as_bytes()).unwrap(), contents)
+    }
+
+    pub(crate) fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wickdl-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn check(name: &str, parts: &[(u8, u32, u32)], damage: fn(&mut Vec<u8>)) -> Option<CorruptFile> {
+        let (manifest, mut contents) = fixture(parts);
+        damage(&mut contents);
+        let path = temp_file(name, &contents);
+        let result = verify_file(&manifest.get_files()[0], &path, &chunk_checks(&manifest)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn assert_range(range: &CorruptRange, position: u64, length: u32, chunk: u8, status: RangeStatus) {
+        assert_eq!((range.position, range.length), (position, length));
+        assert_eq!(range.chunk_guid, chunk_guid(chunk));
+        assert_eq!(range.status, status);
+    }
+
+    #[test]
+    fn intact_file_passes() {
+        assert!(check("intact", &[(1, 0, WINDOW), (2, 0, WINDOW)], |_| {}).is_none());
+    }
+
+    #[test]
+    fn narrows_down_to_the_corrupt_chunk() {
+        let corrupt = check("flipped", &[(1, 0, WINDOW), (2, 0, WINDOW), (3, 0, WINDOW)], |v| v[WINDOW as usize + 5] ^= 0xFF).unwrap();
+        assert_eq!((corrupt.expected_size, corrupt.actual_size), (3 * WINDOW as u64, 3 * WINDOW as u64));
+        assert_ne!(corrupt.expected_hash, corrupt.actual_hash);
+        assert_eq!(corrupt.ranges.len(), 1);
+        assert_range(&corrupt.ranges[0], WINDOW as u64, WINDOW, 2, RangeStatus::Corrupt);
+    }
+
+    #[test]
+    fn truncated_parts_are_corrupt() {
+        let corrupt = check("truncated", &[(1, 0, WINDOW), (2, 0, WINDOW)], |v| v.truncate(WINDOW as usize + 10)).unwrap();
+        assert_eq!(corrupt.actual_size, WINDOW as u64 + 10);
+        assert_eq!(corrupt.ranges.len(), 1);
+        assert_range(&corrupt.ranges[0], WINDOW as u64, WINDOW, 2, RangeStatus::Corrupt);
+    }
+
+    #[test]
+    fn partial_chunks_are_unverifiable() {
+        let corrupt = check("partial", &[(1, 0, WINDOW), (3, 10, 100)], |v| v[WINDOW as usize + 1] ^= 0xFF).unwrap();
+        assert_eq!(corrupt.ranges.len(), 1);
+        assert_range(&corrupt.ranges[0], WINDOW as u64, 100, 3, RangeStatus::Unverifiable);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::err::{WickError, WickResult};
+use std::fmt;
+
+// A launcher build version such as ++Fortnite+Release-17.30-CL-17004569-Windows.
+// Field order matters here, builds order by version number and then changelist.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BuildVersion {
+    major: u32,
+    minor: u32,
+    changelist: u32,
+    branch: String,
+    platform: String,
+}
+
+impl BuildVersion {
+    pub fn parse(version: &str) -> WickResult<Self> {
+        let invalid = || WickError::new_str(format!("Invalid build version: {}", version), 12);
+
+        let cl_pos = version.find("-CL-").ok_or_else(invalid)?;
+        let branch = &version[..cl_pos];
+        let remaining = &version[(cl_pos + 4)..];
+        let (changelist, platform) = match remaining.find('-') {
+            Some(pos) => (&remaining[..pos], &remaining[(pos + 1)..]),
+            None => (remaining, ""),
+        };
+
+        let number = match branch.rfind('-') {
+            Some(pos) => &branch[(pos + 1)..],
+            None => return Err(invalid()),
+        };
+        let mut parts = number.split('.');
+        let major = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        let minor = match parts.next() {
+            Some(minor) => minor.parse().map_err(|_| invalid())?,
+            None => 0,
+        };
+
+        Ok(Self {
+            major,
+            minor,
+            changelist: changelist.parse().map_err(|_| invalid())?,
+            branch: branch.to_owned(),
+            platform: platform.to_owned(),
+        })
+    }
+
+    pub fn get_branch(&self) -> &str {
+        &self.branch
+    }
+
+    pub fn get_major(&self) -> u32 {
+        self.major
+    }
+
+    pub fn get_minor(&self) -> u32 {
+        self.minor
+    }
+
+    pub fn get_changelist(&self) -> u32 {
+        self.changelist
+    }
+
+    pub fn get_platform(&self) -> &str {
+        &self.platform
+    }
+}
+
+impl fmt::Display for BuildVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-CL-{}", self.branch, self.changelist)?;
+        if !self.platform.is_empty() {
+            write!(f, "-{}", self.platform)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(version: &str) -> BuildVersion {
+        BuildVersion::parse(version).unwrap()
+    }
+
+    #[test]
+    fn parses_every_field() {
+        let version = parse("++Fortnite+Release-17.30-CL-17004569-Windows");
+        assert_eq!(version.get_branch(), "++Fortnite+Release-17.30");
+        assert_eq!(version.get_major(), 17);
+        assert_eq!(version.get_minor(), 30);
+        assert_eq!(version.get_changelist(), 17004569);
+        assert_eq!(version.get_platform(), "Windows");
+        assert_eq!(version.to_string(), "++Fortnite+Release-17.30-CL-17004569-Windows");
+    }
+
+    #[test]
+    fn platform_is_optional() {
+        let version = parse("++Fortnite+Release-17.30-CL-17004569");
+        assert_eq!(version.get_changelist(), 17004569);
+        assert_eq!(version.get_platform(), "");
+        assert_eq!(version.to_string(), "++Fortnite+Release-17.30-CL-17004569");
+    }
+
+    #[test]
+    fn orders_by_version_then_changelist() {
+        let mut versions = vec![
+            parse("++Fortnite+Release-17.30-CL-17004569-Windows"),
+            parse("++Fortnite+Release-16.50-CL-16469788-Windows"),
+            parse("++Fortnite+Release-17.30-CL-16999999-Windows"),
+            parse("++Fortnite+Release-18.00-CL-17004000-Windows"),
+            parse("++Fortnite+Release-17.40-CL-17000000-Windows"),
+        ];
+        versions.sort();
+        let changelists: Vec<u32> = versions.iter().map(|v| v.get_changelist()).collect();
+        assert_eq!(changelists, vec![16469788, 16999999, 17004569, 17000000, 17004000]);
+        // The minor version wins over a larger changelist
+        assert!(parse("++Fortnite+Release-17.40-CL-1-Windows") > parse("++Fortnite+Release-17.30-CL-99999999-Windows"));
+    }
+
+    #[test]
+    fn needs_a_changelist() {
+        assert_eq!(BuildVersion::parse("++Fortnite+Release-17.30-17004569-Windows").unwrap_err().get_code(), 12);
+        assert!(BuildVersion::parse("++Fortnite+Release-17.30-CL-Windows").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{ServiceState, UtocService};
+use crate::err::{self, WickResult};
+use futures::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+struct FileEntry {
+    container: usize,
+    // The path as the container knows it
+    name: String,
+}
+
+struct Index {
+    containers: Vec<(String, Arc<UtocService>)>,
+    // Containers that couldn't be opened, like encrypted ones without a key, and why
+    failed: Vec<(String, String)>,
+    files: BTreeMap<String, FileEntry>,
+}
+
+// Patch containers (pakchunk0_s1_P, pakchunk0_2_P) are mounted after everything else, later patches winning
+fn container_priority(container: &str) -> u32 {
+    let stem = container.trim_end_matches(".utoc");
+    let stem = match stem.strip_suffix("_P") {
+        Some(stem) => stem,
+        None => return 0,
+    };
+    let patch = stem.rsplit('_').next().and_then(|v| v.parse::<u32>().ok()).unwrap_or(0);
+    1 + patch
+}
+
+fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let mut path = path.as_str();
+    while let Some(rest) = path.strip_prefix("../") {
+        path = rest;
+    }
+    path.trim_start_matches('/').to_owned()
+}
+
+// One path namespace over every .utoc in a build. The containers are only fetched the first time
+// something needs the index.
+pub struct BuildFileSystem<'a> {
+    state: &'a ServiceState,
+    index: Mutex<Option<Arc<Index>>>,
+}
+
+impl<'a> BuildFileSystem<'a> {
+    pub(crate) fn new(state: &'a ServiceState) -> Self {
+        Self {
+            state,
+            index: Mutex::new(None),
+        }
+    }
+
+    async fn get_index(&self) -> WickResult<Arc<Index>> {
+        let mut index = self.index.lock().await;
+        if let Some(index) = index.as_ref() {
+            return Ok(Arc::clone(index));
+        }
+
+        let mut names: Vec<String> = self.state.files.iter().filter(|v| v.filename.ends_with(".utoc")).map(|v| v.filename.clone()).collect();
+        names.sort_by_key(|v| (container_priority(v), v.clone()));
+        // Buffered keeps them in priority order
+        let opened: Vec<(String, WickResult<UtocService>)> = stream::iter(names.into_iter().map(|name| async move {
+            let service = self.state.get_utoc(&name).await;
+            (name, service)
+        })).buffered(self.state.request_count).collect().await;
+
+        let mut files = BTreeMap::new();
+        let mut containers = Vec::new();
+        let mut failed = Vec::new();
+        for (name, service) in opened {
+            let service = match service {
+                Ok(service) => service,
+                Err(e) => {
+                    failed.push((name, e.to_string()));
+                    continue;
+                },
+            };
+            let idx = containers.len();
+            let mount_point = service.get_mount_point().to_owned();
+            for file in service.get_file_list() {
+                // Inserted lowest priority first, so overridden files get replaced
+                files.insert(normalize_path(&(mount_point.clone() + file)), FileEntry {
+                    container: idx,
+                    name: file.clone(),
+                });
+            }
+            containers.push((name, Arc::new(service)));
+        }
+
+        let built = Arc::new(Index {
+            containers,
+            failed,
+            files,
+        });
+        *index = Some(Arc::clone(&built));
+        Ok(built)
+    }
+
+    pub async fn exists(&self, path: &str) -> WickResult<bool> {
+        Ok(self.get_index().await?.files.contains_key(&normalize_path(path)))
+    }
+
+    // Which container a path will be read from
+    pub async fn get_container(&self, path: &str) -> WickResult<Option<String>> {
+        let index = self.get_index().await?;
+        Ok(index.files.get(&normalize_path(path)).map(|v| index.containers[v.container].0.clone()))
+    }
+
+    // Containers left out of the namespace because they failed to open, with the error for each
+    pub async fn get_failed_containers(&self) -> WickResult<Vec<(String, String)>> {
+        Ok(self.get_index().await?.failed.clone())
+    }
+
+    pub async fn get_file_list(&self) -> WickResult<Vec<String>> {
+        Ok(self.get_index().await?.files.keys().cloned().collect())
+    }
+
+    // Files and directories directly under a directory. Directories end with a slash.
+    pub async fn list_dir(&self, dir: &str) -> WickResult<Vec<String>> {
+        let index = self.get_index().await?;
+        let mut prefix = normalize_path(dir);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        let mut entries: Vec<String> = Vec::new();
+        for path in index.files.range(prefix.clone()..).map(|(k, _)| k).take_while(|v| v.starts_with(&prefix)) {
+            let rest = &path[prefix.len()..];
+            let entry = match rest.find('/') {
+                Some(pos) => &rest[..(pos + 1)],
+                None => rest,
+            };
+            if entries.last().map(|v| v.as_str()) != Some(entry) {
+                entries.push(entry.to_owned());
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub async fn open(&self, path: &str) -> WickResult<Vec<u8>> {
+        let index = self.get_index().await?;
+        let entry = match index.files.get(&normalize_path(path)) {
+            Some(entry) => entry,
+            None => return err::make_err("File not found"),
+        };
+        index.containers[entry.container].1.get_file(&entry.name).await
+    }
+}
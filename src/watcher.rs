@@ -0,0 +1,226 @@
+use crate::http::HttpService;
+use crate::auth::{AuthProvider, TokenManager};
+use crate::err::WickResult;
+use crate::manifest::{self, AppManifest, ManifestQuery, LAUNCHER_URL};
+use john_wick_parse::manifest::Manifest;
+use futures::stream::{self, Stream};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub struct NewBuild {
+    app_manifest: AppManifest,
+    chunk_manifest: Manifest,
+}
+
+impl NewBuild {
+    pub fn get_app_manifest(&self) -> &AppManifest {
+        &self.app_manifest
+    }
+
+    pub fn get_chunk_manifest(&self) -> &Manifest {
+        &self.chunk_manifest
+    }
+
+    pub fn into_manifests(self) -> (AppManifest, Manifest) {
+        (self.app_manifest, self.chunk_manifest)
+    }
+}
+
+// Polls the launcher and reports each build version it hasn't seen before.
+// Unless told about a starting build, the first poll reports whatever is current.
+pub struct BuildWatcher {
+    http: Arc<HttpService>,
+    tokens: TokenManager,
+    query: ManifestQuery,
+    launcher_url: String,
+    interval: Duration,
+    etag: Option<String>,
+    last_build: Option<String>,
+    next_poll: Instant,
+}
+
+impl BuildWatcher {
+    pub fn new(query: ManifestQuery, interval: Duration) -> Self {
+        Self {
+            http: Arc::new(HttpService::new()),
+            tokens: TokenManager::default(),
+            query,
+            launcher_url: LAUNCHER_URL.to_owned(),
+            interval,
+            etag: None,
+            last_build: None,
+            next_poll: Instant::now(),
+        }
+    }
+
+    pub fn http(mut self, http: Arc<HttpService>) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn auth<P>(mut self, provider: P) -> Self where P: AuthProvider + 'static {
+        self.tokens = TokenManager::new(Box::new(provider));
+        self
+    }
+
+    pub fn tokens(mut self, tokens: TokenManager) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    pub fn launcher_url(mut self, launcher_url: &str) -> Self {
+        self.launcher_url = launcher_url.to_owned();
+        self
+    }
+
+    // Only report builds other than this one
+    pub fn since(mut self, build_version: &str) -> Self {
+        self.last_build = Some(build_version.to_owned());
+        self
+    }
+
+    async fn poll(&mut self) -> WickResult<Option<NewBuild>> {
+        let polled = manifest::poll_manifest(&self.http, &self.tokens, &self.query, &self.launcher_url, self.etag.as_deref()).await?;
+        let (app_manifest, etag) = match polled {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+
+        if self.last_build.as_deref() == Some(app_manifest.get_build_version()) {
+            self.etag = etag;
+            return Ok(None);
+        }
+
+        // Only remember the build once the chunk manifest is in hand, so a failure here is retried next poll
+        let chunk_data = manifest::get_chunk_manifest_data(&self.http, &app_manifest).await?;
+        let chunk_manifest = manifest::parse_chunk_manifest(&chunk_data)?;
+        self.etag = etag;
+        self.last_build = Some(app_manifest.get_build_version().to_owned());
+
+        Ok(Some(NewBuild {
+            app_manifest,
+            chunk_manifest,
+        }))
+    }
+
+    // Errors are passed along without ending the stream, the next poll carries on as normal.
+    pub fn into_stream(self) -> impl Stream<Item = WickResult<NewBuild>> {
+        stream::unfold(self, |mut watcher| async move {
+            loop {
+                tokio::time::sleep_until(watcher.next_poll).await;
+                watcher.next_poll = Instant::now() + watcher.interval;
+                match watcher.poll().await {
+                    Ok(Some(build)) => return Some((Ok(build), watcher)),
+                    Ok(None) => {},
+                    Err(e) => return Some((Err(e), watcher)),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NoAuth;
+    use crate::test_server::{TestResponse, TestServer};
+    use futures::StreamExt;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    const CHUNK_MANIFEST: &'static str = r#"{ "ManifestFileVersion": "012000000000", "FileManifestList": [], "ChunkHashList": {} }"#;
+
+    fn app_manifest(cdn: &TestServer, build_version: &str) -> String {
+        format!(r#"{{
+            "appName": "Fortnite", "labelName": "Live", "buildVersion": "{}",
+            "catalogItemId": "4fe75bbc5a674f4f9b356b5c90567da5", "expires": "2030-01-01T00:00:00.000Z", "assetId": "Fortnite",
+            "items": {{ "MANIFEST": {{ "signature": "sig", "distribution": "{}/", "path": "Builds/Test/CloudDir/test.manifest", "additionalDistributions": [] }} }}
+        }}"#, build_version, cdn.get_url())
+    }
+
+    // The launcher gives the responses in order, then keeps saying nothing has changed
+    async fn launcher(responses: Vec<TestResponse>) -> TestServer {
+        let responses = Mutex::new(responses.into_iter().collect::<VecDeque<_>>());
+        TestServer::start(move |_| responses.lock().unwrap().pop_front().unwrap_or_else(|| TestResponse::new(304, ""))).await
+    }
+
+    async fn cdn() -> TestServer {
+        TestServer::start(|_| TestResponse::new(200, CHUNK_MANIFEST)).await
+    }
+
+    fn watch(launcher: &TestServer) -> impl Stream<Item = WickResult<NewBuild>> {
+        BuildWatcher::new(ManifestQuery::default(), Duration::from_millis(10)).auth(NoAuth).launcher_url(launcher.get_url()).into_stream()
+    }
+
+    fn build(app_manifest: String, etag: &str) -> TestResponse {
+        TestResponse::new(200, &app_manifest).header("ETag", etag)
+    }
+
+    #[tokio::test]
+    async fn first_poll_yields_the_current_build() {
+        let cdn = cdn().await;
+        let launcher = launcher(vec![build(app_manifest(&cdn, "++Fortnite+Release-17.30-CL-17004569"), "\"e1\"")]).await;
+        let mut builds = Box::pin(watch(&launcher));
+
+        let first = builds.next().await.unwrap().unwrap();
+        assert_eq!(first.get_app_manifest().get_build_version(), "++Fortnite+Release-17.30-CL-17004569");
+        assert!(first.get_chunk_manifest().get_files().is_empty());
+        let fetched = cdn.get_requests();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].path, "/Builds/Test/CloudDir/test.manifest?sig");
+    }
+
+    #[tokio::test]
+    async fn unchanged_builds_yield_nothing() {
+        let cdn = cdn().await;
+        let launcher = launcher(vec![
+            build(app_manifest(&cdn, "++Fortnite+Release-17.30-CL-17004569"), "\"e1\""),
+            TestResponse::new(304, ""),
+            build(app_manifest(&cdn, "++Fortnite+Release-17.30-CL-17004569"), "\"e2\""),
+            build(app_manifest(&cdn, "++Fortnite+Release-17.40-CL-17269705"), "\"e3\""),
+        ]).await;
+        let mut builds = Box::pin(watch(&launcher));
+
+        builds.next().await.unwrap().unwrap();
+        let next = builds.next().await.unwrap().unwrap();
+        assert_eq!(next.get_app_manifest().get_build_version(), "++Fortnite+Release-17.40-CL-17269705");
+        assert_eq!(launcher.get_requests().len(), 4);
+        // The repeat wasn't fetched again
+        assert_eq!(cdn.get_requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn errors_dont_end_the_stream() {
+        let cdn = cdn().await;
+        let launcher = launcher(vec![
+            TestResponse::new(503, r#"{ "errorCode": "errors.com.epicgames.common.server_error" }"#),
+            build(app_manifest(&cdn, "++Fortnite+Release-17.30-CL-17004569"), "\"e1\""),
+        ]).await;
+        let mut builds = Box::pin(watch(&launcher));
+
+        let err = builds.next().await.unwrap().err().unwrap();
+        assert_eq!(err.get_epic_error().unwrap().get_error_code(), "errors.com.epicgames.common.server_error");
+        let next = builds.next().await.unwrap().unwrap();
+        assert_eq!(next.get_app_manifest().get_build_version(), "++Fortnite+Release-17.30-CL-17004569");
+    }
+
+    #[tokio::test]
+    async fn sends_the_etag_back() {
+        let cdn = cdn().await;
+        let launcher = launcher(vec![
+            build(app_manifest(&cdn, "++Fortnite+Release-17.30-CL-17004569"), "\"e1\""),
+            TestResponse::new(304, ""),
+            build(app_manifest(&cdn, "++Fortnite+Release-17.40-CL-17269705"), "\"e2\""),
+        ]).await;
+        let mut builds = Box::pin(watch(&launcher));
+
+        builds.next().await.unwrap().unwrap();
+        builds.next().await.unwrap().unwrap();
+        let requests = launcher.get_requests();
+        assert_eq!(requests[0].header("If-None-Match"), None);
+        assert_eq!(requests[1].header("If-None-Match"), Some("\"e1\""));
+        // A 304 leaves the last ETag in place
+        assert_eq!(requests[2].header("If-None-Match"), Some("\"e1\""));
+    }
+}